@@ -11,50 +11,351 @@
 use std::{net::IpAddr, time::Duration};
 
 use domain::{
-    base::{Message, RecordSection, Rtype},
+    base::{iana::OptRcode, name::ParsedName, Message, ParsedRecord, RecordSection, Rtype, ToName},
     rdata,
 };
 
-pub(crate) struct Iter<'a>(RecordSection<'a, Vec<u8>>);
+use crate::answer::{AddressRecord, EchoedQuestion};
 
-impl<'a> Iterator for Iter<'a> {
-    type Item = (IpAddr, Duration);
+/// The address records of the answer section owned by the end of the CNAME chain starting from
+/// `qname`, along with the number of CNAME records followed.
+///
+/// The chain is followed before the addresses are picked, so the records may come in any order.
+/// The records that can't be parsed, e.g. with malformed data, are skipped.
+fn addresses<'a>(
+    records: RecordSection<'a, Vec<u8>>,
+    qname: Option<ParsedName<&'a [u8]>>,
+) -> (Vec<AddressRecord>, usize) {
+    let cnames: Vec<_> = records
+        .filter_map(Result::ok)
+        .filter(|record| record.rtype() == Rtype::CNAME)
+        .filter_map(|record| {
+            let Ok(Some(record)) = record.into_record::<rdata::Cname<ParsedName<&'a [u8]>>>()
+            else {
+                return None;
+            };
+            let owner = record.owner().clone();
+            Some((owner, record.into_data().into_cname()))
+        })
+        .collect();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(Ok(record)) = self.0.next() {
-            match record.rtype() {
-                Rtype::A => {
-                    let ttl = record.ttl().into_duration();
-                    let data: rdata::A = record.into_record().unwrap().unwrap().into_data();
-                    let addr = IpAddr::V4(data.addr());
-                    Some((addr, ttl))
-                }
-                Rtype::AAAA => {
-                    let ttl = record.ttl().into_duration();
-                    let data: rdata::Aaaa = record.into_record().unwrap().unwrap().into_data();
-                    let addr = IpAddr::V6(data.addr());
-                    Some((addr, ttl))
-                }
-                _ => None,
-            }
-        } else {
-            None
+    // `name_eq` compares case-insensitively, the names themselves are left as sent.
+    let mut target = qname;
+    let mut hops = 0;
+    if let Some(target) = &mut target {
+        // Each CNAME record is followed at most once, so that a loop ends.
+        while hops < cnames.len() {
+            let Some((_, cname)) = cnames.iter().find(|(owner, _)| owner.name_eq(target)) else {
+                break;
+            };
+            *target = cname.clone();
+            hops += 1;
         }
     }
+
+    let addrs = records
+        .filter_map(Result::ok)
+        .filter(|record| target.as_ref().map_or(true, |x| record.owner().name_eq(x)))
+        .filter_map(address)
+        .collect();
+    (addrs, hops)
+}
+
+/// The address of an A or AAAA record, `None` for the other types and the malformed records.
+fn address(record: ParsedRecord<'_, Vec<u8>>) -> Option<AddressRecord> {
+    let owner = record.owner().to_string();
+    let class = record.class();
+    let ttl = record.ttl().into_duration();
+    let ip = match record.rtype() {
+        Rtype::A => {
+            let Ok(Some(record)) = record.into_record::<rdata::A>() else {
+                return None;
+            };
+            IpAddr::V4(record.data().addr())
+        }
+        Rtype::AAAA => {
+            let Ok(Some(record)) = record.into_record::<rdata::Aaaa>() else {
+                return None;
+            };
+            IpAddr::V6(record.data().addr())
+        }
+        _ => return None,
+    };
+    Some(AddressRecord {
+        owner,
+        class,
+        ip,
+        ttl,
+    })
 }
 
+/// The addresses of a response, parsed once out of its answer section.
 pub(crate) struct IpAddresses {
-    message: Message<Vec<u8>>,
+    addrs: Vec<AddressRecord>,
+    rcode: OptRcode,
+    authenticated: bool,
+    question: Option<EchoedQuestion>,
+    cname_chain: usize,
 }
 
 impl IpAddresses {
-    pub(crate) fn iter(&self) -> Iter {
-        Iter(self.message.answer().unwrap())
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (IpAddr, Duration)> + '_ {
+        self.addrs.iter().map(|x| (x.ip, x.ttl))
+    }
+
+    pub(crate) fn records(&self) -> impl Iterator<Item = &AddressRecord> + '_ {
+        self.addrs.iter()
+    }
+
+    /// Whether the name exists at all, i.e. the response is not NXDOMAIN.
+    pub(crate) fn exists(&self) -> bool {
+        self.rcode != OptRcode::NXDOMAIN
+    }
+
+    pub(crate) fn rcode(&self) -> OptRcode {
+        self.rcode
+    }
+
+    /// Whether the nameserver set the AD bit, i.e. it validated the response with DNSSEC.
+    pub(crate) fn authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    pub(crate) fn question(&self) -> Option<&EchoedQuestion> {
+        self.question.as_ref()
+    }
+
+    /// The number of CNAME records followed from the question to the addresses.
+    pub(crate) fn cname_chain(&self) -> usize {
+        self.cname_chain
     }
 }
 
 impl From<Message<Vec<u8>>> for IpAddresses {
     fn from(message: Message<Vec<u8>>) -> Self {
-        Self { message }
+        let (addrs, cname_chain) = match message.answer() {
+            Ok(records) => addresses(records, message.first_question().map(|q| q.into_qname())),
+            Err(_) => (Vec::new(), 0),
+        };
+        let rcode = rcode(&message);
+        let authenticated = message.header().ad();
+        let question = message.first_question().map(|q| EchoedQuestion {
+            name: q.qname().to_string(),
+            rtype: q.qtype(),
+            class: q.qclass(),
+        });
+        Self {
+            addrs,
+            rcode,
+            authenticated,
+            question,
+            cname_chain,
+        }
+    }
+}
+
+/// The full RCODE of the response, the 4 bits of the header along with the 8 extended bits of
+/// the OPT record if there is one (RFC 6891, section 6.1.3).
+pub(crate) fn rcode(message: &Message<Vec<u8>>) -> OptRcode {
+    match message.opt() {
+        Some(opt) => opt.rcode(message.header()),
+        None => OptRcode::from_parts(message.header().rcode(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use domain::base::{
+        iana::{Class, Rcode},
+        name::UncertainName,
+        rdata::UnknownRecordData,
+        MessageBuilder, Name, Question, Record, Ttl,
+    };
+
+    use super::*;
+
+    enum Data<'a> {
+        Cname(&'a str),
+        Addr(&'a str),
+        Raw(Rtype, &'a [u8]),
+    }
+
+    fn name(name: &str) -> Name<Vec<u8>> {
+        let name = UncertainName::<Vec<u8>>::from_str(name).unwrap();
+        name.into_absolute().unwrap()
+    }
+
+    /// A NOERROR response to the A question for `qname`, with the given answers in order.
+    fn response(qname: &str, answers: &[(&str, Data)]) -> Message<Vec<u8>> {
+        let mut message = MessageBuilder::new_vec();
+        message.header_mut().set_qr(true);
+        let mut message = message.question();
+        message
+            .push(Question::new(name(qname), Rtype::A, Class::IN))
+            .unwrap();
+        let mut message = message.answer();
+        let ttl = Ttl::from_secs(60);
+        for (owner, data) in answers {
+            let owner = name(owner);
+            match data {
+                Data::Cname(target) => {
+                    let data = rdata::Cname::new(name(target));
+                    message.push(Record::new(owner, Class::IN, ttl, data))
+                }
+                Data::Addr(ip) => match IpAddr::from_str(ip).unwrap() {
+                    IpAddr::V4(ip) => {
+                        message.push(Record::new(owner, Class::IN, ttl, rdata::A::new(ip)))
+                    }
+                    IpAddr::V6(ip) => {
+                        message.push(Record::new(owner, Class::IN, ttl, rdata::Aaaa::new(ip)))
+                    }
+                },
+                Data::Raw(rtype, bytes) => {
+                    let data = UnknownRecordData::from_octets(*rtype, bytes.to_vec()).unwrap();
+                    message.push(Record::new(owner, Class::IN, ttl, data))
+                }
+            }
+            .unwrap();
+        }
+        message.into_message()
+    }
+
+    fn ips(addrs: &IpAddresses) -> Vec<IpAddr> {
+        addrs.iter().map(|(ip, _)| ip).collect()
+    }
+
+    #[test]
+    fn cname_target_in_another_case() {
+        let message = response(
+            "www.example.test",
+            &[
+                ("www.example.test", Data::Cname("Target.Example.TEST")),
+                ("tARGET.example.test", Data::Addr("192.0.2.1")),
+            ],
+        );
+        let addrs = IpAddresses::from(message);
+        assert_eq!(ips(&addrs), [IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(addrs.cname_chain(), 1);
+        // The names are left as sent.
+        let owners: Vec<_> = addrs
+            .records()
+            .map(|x| x.owner.trim_end_matches('.'))
+            .collect();
+        assert_eq!(owners, ["tARGET.example.test"]);
+    }
+
+    #[test]
+    fn addresses_before_their_cname() {
+        let message = response(
+            "www.example.test",
+            &[
+                ("target.example.test", Data::Addr("192.0.2.1")),
+                ("other.example.test", Data::Addr("192.0.2.9")),
+                ("www.example.test", Data::Cname("target.example.test")),
+            ],
+        );
+        let addrs = IpAddresses::from(message);
+        assert_eq!(ips(&addrs), [IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(addrs.cname_chain(), 1);
+    }
+
+    #[test]
+    fn malformed_records_are_skipped() {
+        let message = response(
+            "www.example.test",
+            &[
+                ("www.example.test", Data::Raw(Rtype::CNAME, &[0xff])),
+                ("www.example.test", Data::Raw(Rtype::A, &[192, 0, 2])),
+                ("www.example.test", Data::Addr("192.0.2.1")),
+            ],
+        );
+        let addrs = IpAddresses::from(message);
+        assert_eq!(ips(&addrs), [IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(addrs.cname_chain(), 0);
+    }
+
+    #[test]
+    fn rcode_of_the_header() {
+        let mut message = MessageBuilder::new_vec();
+        message.header_mut().set_rcode(Rcode::SERVFAIL);
+        assert_eq!(rcode(&message.into_message()), OptRcode::SERVFAIL);
+    }
+
+    #[test]
+    fn rcode_with_the_extended_bits() {
+        let mut message = MessageBuilder::new_vec().additional();
+        message.header_mut().set_rcode(OptRcode::BADCOOKIE.rcode());
+        message
+            .opt(|opt| {
+                opt.set_rcode(OptRcode::BADCOOKIE);
+                Ok(())
+            })
+            .unwrap();
+        let message = message.into_message();
+        // The header alone would say YXDOMAIN.
+        assert_eq!(message.header().rcode(), Rcode::YXDOMAIN);
+        assert_eq!(rcode(&message), OptRcode::BADCOOKIE);
+    }
+
+    #[test]
+    fn long_cname_chain_is_counted() {
+        let names: Vec<String> = (0..=20).map(|idx| format!("h{idx}.example.test")).collect();
+        let mut answers: Vec<_> = names
+            .windows(2)
+            .map(|pair| (pair[0].as_str(), Data::Cname(&pair[1])))
+            .collect();
+        answers.push((names[20].as_str(), Data::Addr("192.0.2.1")));
+        let addrs = IpAddresses::from(response(&names[0], &answers));
+        // Past the default limit of 8, which fails the lookup.
+        assert_eq!(addrs.cname_chain(), 20);
+        assert_eq!(ips(&addrs), [IpAddr::from([192, 0, 2, 1])]);
+    }
+
+    #[test]
+    fn records_behind_a_cname() {
+        let message = response(
+            "www.example.test",
+            &[
+                ("www.example.test", Data::Cname("target.example.test")),
+                ("target.example.test", Data::Addr("2001:db8::1")),
+            ],
+        );
+        let addrs = IpAddresses::from(message);
+        let records: Vec<_> = addrs.records().collect();
+        assert_eq!(records.len(), 1);
+        // The owner is the target of the chain, not the name asked for.
+        assert_eq!(
+            records[0].owner.trim_end_matches('.'),
+            "target.example.test"
+        );
+        assert_eq!(records[0].class, Class::IN);
+        assert_eq!(records[0].ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(records[0].ttl, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn authenticated_data() {
+        assert!(!IpAddresses::from(response("example.test", &[])).authenticated());
+
+        let mut message = MessageBuilder::new_vec();
+        message.header_mut().set_qr(true);
+        message.header_mut().set_ad(true);
+        assert!(IpAddresses::from(message.into_message()).authenticated());
+    }
+
+    #[test]
+    fn noerror_without_answers() {
+        let addrs = IpAddresses::from(response("example.test", &[]));
+        // The name exists, it only has no addresses.
+        assert!(addrs.exists());
+        assert_eq!(addrs.rcode(), OptRcode::NOERROR);
+        assert_eq!(addrs.iter().count(), 0);
+
+        let mut message = MessageBuilder::new_vec();
+        message.header_mut().set_qr(true);
+        message.header_mut().set_rcode(Rcode::NXDOMAIN);
+        assert!(!IpAddresses::from(message.into_message()).exists());
     }
 }
@@ -0,0 +1,210 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::{net::IpAddr, time::Duration};
+
+use domain::base::iana::{Class, Rtype};
+
+/// The result of a lookup, along with the details of how it was obtained.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Answer {
+    /// Pairs of (ip, ttl).
+    pub addrs: Vec<(IpAddr, Duration)>,
+    /// The questions echoed back by the nameserver, one for each response the addresses
+    /// were taken from. Empty if the answer did not come from the network.
+    pub questions: Vec<EchoedQuestion>,
+    /// The size on the wire of the queries sent.
+    pub query_bytes: usize,
+    /// The size on the wire of the responses received.
+    pub response_bytes: usize,
+    /// The canonical hostname of the `/etc/hosts` entry the addresses were taken from, which
+    /// differs from the name looked up when it is an alias.
+    pub canonical_name: Option<String>,
+    /// Whether the nameserver set the AD bit on all the responses, i.e. it validated them with
+    /// DNSSEC. This is only as trustworthy as the path to the nameserver, so the bit is ignored
+    /// unless `options trust-ad` is set in `/etc/resolv.conf`.
+    pub authenticated: bool,
+    /// Where the time of the lookup went, if [`StubResolver::with_collect_timings`] is set.
+    ///
+    /// [`StubResolver::with_collect_timings`]: crate::StubResolver::with_collect_timings
+    pub timings: Option<LookupTimings>,
+    /// Whether the nameserver said the name exists but has no addresses (NODATA), as opposed
+    /// to [`Error::NxDomain`](crate::Error::NxDomain) when it does not exist at all. A family
+    /// without addresses is not NODATA if the other family has some.
+    pub nodata: bool,
+    /// Where the addresses came from, `None` if no source had an answer.
+    pub origin: Option<Origin>,
+    /// The records of the responses the addresses were taken from. Empty if the answer did not
+    /// come from the network.
+    pub records: Vec<AddressRecord>,
+    /// The IDs of the queries the addresses were asked for, one for each family, to match the
+    /// lookup with a packet capture. Empty if the answer did not come from the network.
+    pub transaction_ids: Vec<u16>,
+    /// How many times the queries had to be sent before the nameserver answered, over UDP then
+    /// TCP, 1 if it answered the first one. 0 if the answer did not come from the network.
+    pub attempts_used: u8,
+    /// Where each of the addresses came from, in the same order, when the sources are merged
+    /// and they may differ. Empty otherwise, all of them coming from `origin`.
+    pub(crate) origins: Vec<Origin>,
+}
+
+impl Answer {
+    pub(crate) fn from_addrs(addrs: Vec<(IpAddr, Duration)>, origin: Origin) -> Self {
+        Self {
+            addrs,
+            origin: Some(origin),
+            ..Default::default()
+        }
+    }
+
+    /// A hash of the set of addresses, ignoring their order and TTL, to tell cheaply whether the
+    /// addresses of a name changed between two lookups.
+    ///
+    /// This is the 64-bit FNV-1a hash of the sorted addresses, so it does not change across
+    /// versions of Rust nor runs of the program.
+    pub fn fingerprint(&self) -> u64 {
+        let mut ips: Vec<IpAddr> = self.addrs.iter().map(|(ip, _)| *ip).collect();
+        ips.sort_unstable();
+        ips.dedup();
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        for ip in ips {
+            // The family is hashed too, so that the addresses of one can't collide with the other.
+            match ip {
+                IpAddr::V4(ip) => {
+                    write(&[4]);
+                    write(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    write(&[6]);
+                    write(&ip.octets());
+                }
+            }
+        }
+        hash
+    }
+}
+
+/// Where an address came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The response of a nameserver.
+    Dns,
+    /// An entry of `/etc/hosts`.
+    HostsFile,
+    /// A name set with [`StubResolver::override_name`](crate::StubResolver::override_name).
+    Override,
+    /// The name looked up was the address itself.
+    Literal,
+}
+
+/// An address returned by a lookup, along with where it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedAddr {
+    pub ip: IpAddr,
+    pub ttl: Duration,
+    pub origin: Origin,
+}
+
+/// An A or AAAA record of a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressRecord {
+    /// The name owning the record, the target of the CNAME chain when the name looked up is an
+    /// alias.
+    pub owner: String,
+    pub class: Class,
+    pub ip: IpAddr,
+    pub ttl: Duration,
+}
+
+/// The question section of a response, as the nameserver sent it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EchoedQuestion {
+    /// The queried name, in the case used by the nameserver.
+    pub name: String,
+    pub rtype: Rtype,
+    pub class: Class,
+}
+
+/// A URI record, pointing at the URI of a service (RFC 7553).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UriRecord {
+    /// Lower is tried first.
+    pub priority: u16,
+    /// The relative weight of the records of the same priority.
+    pub weight: u16,
+    pub target: String,
+}
+
+/// Where the time of a lookup went.
+///
+/// The network, parsing and name construction times are the ones of the query the answer came
+/// from, the total covers the failed attempts too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LookupTimings {
+    /// Looking in the overrides and `/etc/hosts`.
+    pub local: Duration,
+    /// Turning the name into an absolute name.
+    pub name_construction: Duration,
+    /// Sending the queries and waiting for the responses.
+    pub network: Duration,
+    /// Parsing the responses.
+    pub parsing: Duration,
+    /// The whole lookup.
+    pub total: Duration,
+}
+
+/// A record of a zone, as read from a zone transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZoneRecord {
+    pub owner: String,
+    pub rtype: Rtype,
+    pub class: Class,
+    pub ttl: Duration,
+    /// The data of the record, in the presentation format of zone files.
+    pub data: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(addrs: &[(&str, u64)]) -> Answer {
+        let addrs = addrs
+            .iter()
+            .map(|(ip, ttl)| (ip.parse().unwrap(), Duration::from_secs(*ttl)))
+            .collect();
+        Answer::from_addrs(addrs, Origin::Dns)
+    }
+
+    #[test]
+    fn fingerprint_ignores_order_and_ttl() {
+        let a = answer(&[("192.0.2.1", 60), ("2001:db8::1", 60), ("192.0.2.2", 60)]);
+        let b = answer(&[("192.0.2.2", 5), ("192.0.2.1", 300), ("2001:db8::1", 1)]);
+        assert_eq!(a.fingerprint(), b.fingerprint());
+
+        let duplicated = answer(&[("192.0.2.1", 60), ("192.0.2.1", 60), ("2001:db8::1", 60)]);
+        let deduplicated = answer(&[("2001:db8::1", 60), ("192.0.2.1", 60)]);
+        assert_eq!(duplicated.fingerprint(), deduplicated.fingerprint());
+    }
+
+    #[test]
+    fn fingerprint_changes_with_the_addresses() {
+        let a = answer(&[("192.0.2.1", 60)]);
+        let b = answer(&[("192.0.2.3", 60)]);
+        assert_ne!(a.fingerprint(), b.fingerprint());
+        assert_ne!(a.fingerprint(), answer(&[]).fingerprint());
+    }
+}
@@ -41,6 +41,10 @@ pub enum Error {
         source: domain::base::message::ShortMessage,
     },
     #[snafu(context(false))]
+    MessageParseError {
+        source: domain::base::wire::ParseError,
+    },
+    #[snafu(context(false))]
     MessagePushError {
         source: domain::base::message_builder::PushError,
     },
@@ -62,4 +66,34 @@ pub enum Error {
         expected: u16,
         found: u16,
     },
+    NoNameserversConfigured {},
+    NxDomain {},
+    NoResponse {},
+    NoRecords {},
+    NoAddresses {},
+    BlockedAddress {},
+    TransferFailed {
+        rcode: u16,
+    },
+    MalformedTransfer {},
+    AllSearchFailed {
+        attempted: Vec<String>,
+        /// The error of each attempted name, in the same order.
+        errors: Vec<Error>,
+    },
+    ConnectionRefused {
+        nameserver: std::net::SocketAddr,
+    },
+    ResolvConfNotFound {},
+    ResolvConfUnreadable {
+        source: std::io::Error,
+    },
+    ResolvConfParseError {
+        /// The number of the offending line, starting at 1.
+        line: usize,
+    },
+    CnameChainTooLong {},
+    ServerFailure {
+        rcode: u16,
+    },
 }
@@ -0,0 +1,115 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    task::Poll,
+};
+
+use monoio::io::stream::Stream;
+
+/// Poll the futures concurrently, returning their outputs in the order they were given.
+pub(crate) async fn join_all<F: Future>(futures: impl IntoIterator<Item = F>) -> Vec<F::Output> {
+    let mut futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+
+    poll_fn(|cx| {
+        let mut pending = false;
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if let Some(f) = future {
+                match f.as_mut().poll(cx) {
+                    Poll::Ready(value) => {
+                        *output = Some(value);
+                        *future = None;
+                    }
+                    Poll::Pending => pending = true,
+                }
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    })
+    .await;
+
+    outputs.into_iter().flatten().collect()
+}
+
+/// Poll the futures concurrently, returning the output of the first one, in the order they were
+/// given, that is accepted. An output is only returned once all the futures before it are done,
+/// the ones after it are dropped. If no output is accepted, all of them are returned instead.
+pub(crate) async fn first_accepted<F: Future>(
+    futures: impl IntoIterator<Item = F>,
+    accept: impl Fn(&F::Output) -> bool,
+) -> Result<F::Output, Vec<F::Output>> {
+    let mut futures: Vec<_> = futures.into_iter().map(|f| Some(Box::pin(f))).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+
+    let accepted = poll_fn(|cx| {
+        for (future, output) in futures.iter_mut().zip(outputs.iter_mut()) {
+            if let Some(f) = future {
+                if let Poll::Ready(value) = f.as_mut().poll(cx) {
+                    *output = Some(value);
+                    *future = None;
+                }
+            }
+        }
+        for (idx, output) in outputs.iter().enumerate() {
+            match output {
+                Some(output) if accept(output) => return Poll::Ready(Some(idx)),
+                Some(_) => continue,
+                None => return Poll::Pending,
+            }
+        }
+        Poll::Ready(None)
+    })
+    .await;
+
+    match accepted {
+        Some(idx) => Ok(outputs.swap_remove(idx).unwrap()),
+        None => Err(outputs.into_iter().flatten().collect()),
+    }
+}
+
+/// A stream polling the futures concurrently, yielding their outputs as they complete.
+pub(crate) struct Unordered<F> {
+    futures: Vec<Pin<Box<F>>>,
+}
+
+pub(crate) fn unordered<F: Future>(futures: impl IntoIterator<Item = F>) -> Unordered<F> {
+    Unordered {
+        futures: futures.into_iter().map(Box::pin).collect(),
+    }
+}
+
+impl<F: Future> Stream for Unordered<F> {
+    type Item = F::Output;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        if self.futures.is_empty() {
+            return None;
+        }
+
+        let (idx, output) = poll_fn(|cx| {
+            for (idx, future) in self.futures.iter_mut().enumerate() {
+                if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                    return Poll::Ready((idx, output));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        self.futures.swap_remove(idx);
+        Some(output)
+    }
+}
@@ -12,36 +12,90 @@
 #![cfg_attr(not(feature = "global"), forbid(unsafe_code))]
 
 mod addr;
+mod answer;
 mod errors;
+mod future;
 mod lookups;
+mod observer;
+mod options;
 mod parser;
+mod policy;
 #[doc = include_str!("../README.md")]
 pub mod readme;
 mod resolvers;
+#[cfg(test)]
+mod testing;
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeMap, BTreeSet},
     net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
+#[cfg(feature = "global")]
+use std::rc::Rc;
+
+pub use answer::{
+    AddressRecord, Answer, EchoedQuestion, LookupTimings, Origin, ResolvedAddr, UriRecord,
+    ZoneRecord,
+};
+pub use domain::base::iana::{Class, Opcode, Rtype};
 pub use errors::Error;
+pub use observer::{Observer, TcpFallbackReason};
+pub use options::{Family, LookupOptions, Transport};
+pub use policy::{IpPolicy, IpRange};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct HostEntry {
     pub ip: IpAddr,
     pub hosts: BTreeSet<String>,
+    /// The first hostname of the entry, the others being its aliases.
+    pub canonical: Option<String>,
 }
 
 impl HostEntry {
+    /// The first of the hostnames is taken as the canonical one.
     pub fn new(ip: IpAddr, hosts: impl Iterator<Item = String>) -> Self {
+        let mut hosts = hosts.peekable();
+        let canonical = hosts.peek().cloned();
         Self {
             ip,
             hosts: hosts.collect(),
+            canonical,
         }
     }
 }
 
+/// Renders the entry as a hosts file line, the address followed by the canonical hostname and
+/// then its aliases in order.
+impl std::fmt::Display for HostEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ip)?;
+        let aliases = self
+            .hosts
+            .iter()
+            .filter(|host| Some(*host) != self.canonical.as_ref());
+        for (idx, host) in self.canonical.iter().chain(aliases).enumerate() {
+            let separator = if idx == 0 { '\t' } else { ' ' };
+            write!(f, "{separator}{host}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A place a lookup can get its answers from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The names set with [`StubResolver::override_name`].
+    Overrides,
+    /// The entries of `/etc/hosts`.
+    Hosts,
+    /// The nameservers of `/etc/resolv.conf`.
+    Network,
+}
+
 #[cfg(unix)]
+#[derive(Clone)]
 pub struct StubResolver {
     entries: Vec<HostEntry>,
     search: Vec<String>,
@@ -51,11 +105,296 @@ pub struct StubResolver {
     attempts: u8,
     rotate: bool,
     udp_payload_size: u16,
+    trust_ad: bool,
+    source_order: Vec<Source>,
+    min_response_time: Duration,
+    hosts_ttl: Duration,
+    overrides: BTreeMap<String, Vec<(IpAddr, Duration)>>,
+    parallel_search: bool,
+    mdns_unicast: Option<SocketAddr>,
+    padding: Option<u16>,
+    edns_version: Option<u8>,
+    transport: Transport,
+    ip_policy: IpPolicy,
+    tcp_rtypes: Vec<Rtype>,
+    collect_timings: bool,
+    nameserver_groups: Vec<Vec<SocketAddr>>,
+    nameserver_transports: BTreeMap<SocketAddr, Transport>,
+    min_answers: usize,
+    observer: Option<Arc<dyn Observer + Send + Sync>>,
+    in_memory: bool,
+    name_rewriter: Option<Arc<dyn Fn(&str) -> String + Send + Sync>>,
+    sticky_nameserver: bool,
+    sticky_nameservers: Arc<Mutex<resolvers::StickyNameservers>>,
+    max_cname_chain: u8,
+    latencies: Arc<Mutex<BTreeMap<SocketAddr, Duration>>>,
+    merge_sources: bool,
+    family_nameservers: Option<(Vec<SocketAddr>, Vec<SocketAddr>)>,
+    default_nameservers: bool,
+}
+
+// The resolver is meant to be built once, then moved or shared across threads.
+#[cfg(unix)]
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    let _ = assert_send_sync::<StubResolver>;
+};
+
+impl StubResolver {
+    /// Sets the order in which the sources are consulted, defaults to
+    /// `[Overrides, Hosts, Network]`.
+    ///
+    /// The first source with an answer wins, sources left out are not consulted at all.
+    pub fn with_source_order(mut self, source_order: Vec<Source>) -> Self {
+        self.source_order = source_order;
+        self
+    }
+
+    /// Whether to consult all the sources and merge their addresses rather than stopping at the
+    /// first source with an answer, defaults to `false`.
+    ///
+    /// By default a name in `/etc/hosts` is never looked up on the network, as with
+    /// `getaddrinfo(3)`. When merging, the addresses are in the order of the sources, each one
+    /// only once, and the [`Answer::origin`] is the one of the first source with addresses, while
+    /// [`StubResolver::lookup_resolved`] tells the source of each address. A source failing does
+    /// not fail the lookup if another one has an answer.
+    pub fn with_merge_sources(mut self, merge_sources: bool) -> Self {
+        self.merge_sources = merge_sources;
+        self
+    }
+
+    /// Delays the result of a lookup until at least `min_response_time` has passed since it
+    /// started, so that fast answers can't be told apart from slow ones by their timing.
+    pub fn with_min_response_time(mut self, min_response_time: Duration) -> Self {
+        self.min_response_time = min_response_time;
+        self
+    }
+
+    /// Sets the TTL given to answers from `/etc/hosts`, defaults to the largest TTL
+    /// allowed by RFC 2181 (2^31 - 1 seconds).
+    pub fn with_hosts_ttl(mut self, hosts_ttl: Duration) -> Self {
+        self.hosts_ttl = hosts_ttl;
+        self
+    }
+
+    /// Whether to try the name with each of the search domains concurrently rather than one
+    /// after the other, defaults to `false`.
+    ///
+    /// Either way the answer is the one of the first name that resolves, in the `resolv.conf(5)`
+    /// order, so a later name answering quicker does not win over an earlier one.
+    pub fn with_parallel_search(mut self, parallel_search: bool) -> Self {
+        self.parallel_search = parallel_search;
+        self
+    }
+
+    /// Sends the queries for `.local` names to the given unicast mDNS responder (usually
+    /// `127.0.0.1:5353`) instead of the nameservers.
+    pub fn with_mdns_unicast(mut self, addr: SocketAddr) -> Self {
+        self.mdns_unicast = Some(addr);
+        self
+    }
+
+    /// Pads the queries to a multiple of `block` bytes with the EDNS padding option (RFC 7830),
+    /// so their length does not give away the name being asked for.
+    ///
+    /// This only helps over an encrypted transport, in the clear the name can be read anyway.
+    /// RFC 8467 recommends a block size of 128 bytes.
+    pub fn with_padding(mut self, block: u16) -> Self {
+        self.padding = Some(block);
+        self
+    }
+
+    /// Sets the EDNS version advertised in the queries, defaults to `Some(0)`, `None` sends the
+    /// queries without EDNS.
+    ///
+    /// A nameserver that does not support the version answers with BADVERS, in which case the
+    /// query is sent again with the version it supports, or without EDNS.
+    pub fn with_edns_version(mut self, edns_version: Option<u8>) -> Self {
+        self.edns_version = edns_version;
+        self
+    }
+
+    /// Sets how the queries are sent to the nameservers, defaults to [`Transport::Udp`].
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Sets how the queries are sent to the given nameserver, instead of the transport of the
+    /// resolver. The transport of a single lookup, if set, still wins.
+    pub fn with_nameserver_transport(
+        mut self,
+        nameserver: SocketAddr,
+        transport: Transport,
+    ) -> Self {
+        self.nameserver_transports.insert(nameserver, transport);
+        self
+    }
+
+    /// Filters the addresses returned by the lookups against the policy, whichever source they
+    /// come from. A name with only denied addresses fails with [`Error::BlockedAddress`].
+    pub fn with_ip_policy(mut self, ip_policy: IpPolicy) -> Self {
+        self.ip_policy = ip_policy;
+        self
+    }
+
+    /// Sends the queries for the given record types straight over TCP, for the ones whose
+    /// responses are known to be too large for UDP, such as `ANY` or `DNSKEY`.
+    pub fn with_tcp_rtypes(mut self, tcp_rtypes: Vec<Rtype>) -> Self {
+        self.tcp_rtypes = tcp_rtypes;
+        self
+    }
+
+    /// Whether to report where the time of each lookup went in [`Answer::timings`], defaults
+    /// to `false`.
+    pub fn with_collect_timings(mut self, collect_timings: bool) -> Self {
+        self.collect_timings = collect_timings;
+        self
+    }
+
+    /// Treats the addresses as the same nameserver, such as the IPv4 and IPv6 addresses of a
+    /// dual-stack server, with the ones of the `preferred` family first.
+    ///
+    /// When one of the addresses is among the nameservers, all of them are asked in turn in its
+    /// place, so a lookup falls back to the other family of the same server before moving on
+    /// to the next server.
+    pub fn with_nameserver_group(mut self, mut group: Vec<SocketAddr>, preferred: Family) -> Self {
+        group.sort_by_key(|addr| !preferred.matches(&addr.ip()));
+        self.nameserver_groups.push(group);
+        self
+    }
+
+    /// Asks the `v4` nameservers for the IPv4 addresses and the `v6` ones for the IPv6
+    /// addresses, instead of the nameservers of `/etc/resolv.conf`, for the networks where
+    /// each family is best served by its own nameservers.
+    ///
+    /// Both families are still looked up at once, and the name exists if either of them says so.
+    pub fn with_family_nameservers(mut self, v4: Vec<SocketAddr>, v6: Vec<SocketAddr>) -> Self {
+        self.family_nameservers = Some((v4, v6));
+        self
+    }
+
+    /// Moves on to the next nameserver when one answers with fewer than `min_answers`
+    /// addresses, for the names expected to have several. If none has enough, the answer with
+    /// the most addresses is returned.
+    pub fn with_min_answers(mut self, min_answers: usize) -> Self {
+        self.min_answers = min_answers;
+        self
+    }
+
+    /// Tells the observer about the events of the lookups, such as the queries falling back to
+    /// TCP, for monitoring.
+    pub fn with_observer(mut self, observer: impl Observer + Send + Sync + 'static) -> Self {
+        self.observer = Some(Arc::new(observer));
+        self
+    }
+
+    /// Rewrites the names before they are sent to the nameservers, e.g. to append a fixed
+    /// suffix.
+    ///
+    /// The rewrite happens first, the search domains are then appended to the rewritten name.
+    /// The overrides and `/etc/hosts` are looked up with the name as given.
+    pub fn with_name_rewriter(
+        mut self,
+        rewriter: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.name_rewriter = Some(Arc::new(rewriter));
+        self
+    }
+
+    /// Whether to ask the nameserver that last answered for a name first on the next lookups of
+    /// the same name, until it fails, defaults to `false`.
+    ///
+    /// This keeps the answers consistent when the nameservers disagree, as with split-horizon or
+    /// sharded setups. The nameserver is remembered for the last 4096 names looked up, and
+    /// forgotten for all of them when [`reload`](StubResolver::reload) changes the nameservers.
+    pub fn with_sticky_nameserver(mut self, sticky_nameserver: bool) -> Self {
+        self.sticky_nameserver = sticky_nameserver;
+        self
+    }
+
+    /// Sets how many CNAME records a response may chain from the name to its addresses,
+    /// defaults to 8. A longer chain fails the lookup with [`Error::CnameChainTooLong`].
+    pub fn with_max_cname_chain(mut self, max_cname_chain: u8) -> Self {
+        self.max_cname_chain = max_cname_chain;
+        self
+    }
+
+    /// A copy of the resolver with the given changes applied, e.g. to send the queries over TCP
+    /// or give up sooner, without loading the configuration again.
+    ///
+    /// The copy shares what the resolver learned about its nameservers, such as their latencies,
+    /// with the original.
+    pub fn with_overrides(&self, overrides: impl FnOnce(Self) -> Self) -> Self {
+        overrides(self.clone())
+    }
+
+    /// Answers lookups of `name` with the given addresses, replacing any previous override.
+    ///
+    /// The name is matched exactly, ignoring case and a trailing dot.
+    pub fn override_name(
+        &mut self,
+        name: &str,
+        addrs: impl IntoIterator<Item = IpAddr>,
+        ttl: Duration,
+    ) {
+        let addrs = addrs.into_iter().map(|ip| (ip, ttl)).collect();
+        self.overrides.insert(resolvers::override_key(name), addrs);
+    }
 }
 
 #[cfg(feature = "global")]
 thread_local! {
-    pub(crate) static GLOBAL: local_sync::OnceCell<StubResolver> = local_sync::OnceCell::new();
+    pub(crate) static GLOBAL: local_sync::OnceCell<std::cell::RefCell<Rc<StubResolver>>> =
+        local_sync::OnceCell::new();
+}
+
+/// The cell holding the resolver of the current thread.
+///
+/// Only shared references to the cell are handed out, the resolver itself is replaced through the
+/// `RefCell`, which is never borrowed across an await point.
+#[cfg(feature = "global")]
+fn global() -> &'static local_sync::OnceCell<std::cell::RefCell<Rc<StubResolver>>> {
+    GLOBAL.with(|global| unsafe {
+        std::ptr::NonNull::new_unchecked(
+            global as *const _ as *mut local_sync::OnceCell<std::cell::RefCell<Rc<StubResolver>>>,
+        )
+        .as_ref()
+    })
+}
+
+/// The resolver of the current thread, loaded from the system configuration on first use.
+///
+/// The resolver returned is the one at the time of the call, a later [`reload`] replaces it for
+/// the next calls without changing the ones already handed out.
+#[cfg(feature = "global")]
+pub async fn default_resolver() -> Result<Rc<StubResolver>, Error> {
+    let dns = global()
+        .get_or_try_init(|| async {
+            let dns = StubResolver::load().await?;
+            Ok::<_, Error>(std::cell::RefCell::new(Rc::new(dns)))
+        })
+        .await?;
+    Ok(dns.borrow().clone())
+}
+
+/// Completes once the resolver of the current thread has its configuration, loading it if no
+/// other task has started to.
+///
+/// A [`StubResolver`] only exists once its configuration is loaded, so this is only needed for
+/// the resolver of the current thread, e.g. to fail early if the system configuration is
+/// broken rather than on the first lookup.
+#[cfg(feature = "global")]
+pub async fn ready() -> Result<(), Error> {
+    default_resolver().await.map(|_| ())
+}
+
+/// Sets the resolver of the current thread, instead of loading it from the system configuration.
+///
+/// Returns `false` if the resolver was already set or loaded, in which case it is left as is.
+#[cfg(feature = "global")]
+pub fn set_default_resolver(dns: StubResolver) -> bool {
+    global().set(std::cell::RefCell::new(Rc::new(dns))).is_ok()
 }
 
 #[cfg(feature = "global")]
@@ -65,29 +404,47 @@ pub async fn lookup<'a, B>(
 where
     B: FromIterator<(IpAddr, Duration)> + Sized,
 {
-    let global = GLOBAL.with(|global| unsafe {
-        std::ptr::NonNull::new_unchecked(
-            global as *const _ as *mut local_sync::OnceCell<StubResolver>,
-        )
-        .as_ref()
-    });
-
-    let dns: &StubResolver = global.get_or_try_init(|| StubResolver::load()).await?;
+    let dns = default_resolver().await?;
     dns.lookup(host).await
 }
 
+/// Reloads the configuration of the resolver of the current thread, if it was loaded or set.
+///
+/// The reloaded resolver replaces the current one once its configuration is read, the lookups
+/// under way keep the one they started with.
 #[cfg(feature = "global")]
 pub async fn reload() -> Result<(), Error> {
-    let global = GLOBAL.with(|global| unsafe {
-        std::ptr::NonNull::new_unchecked(
-            global as *const _ as *mut local_sync::OnceCell<StubResolver>,
-        )
-        .as_mut()
-    });
+    let Some(dns) = global().get() else {
+        return Ok(());
+    };
+    let mut reloaded = StubResolver::clone(&dns.borrow());
+    reloaded.reload().await?;
+    *dns.borrow_mut() = Rc::new(reloaded);
+    Ok(())
+}
 
-    if let Some(dns) = global.get_mut() {
-        dns.reload().await
-    } else {
-        Ok(())
+#[cfg(all(test, feature = "global"))]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::block_on;
+
+    #[test]
+    fn lookup_uses_the_default_resolver() {
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let map = HashMap::from([("example.test".to_owned(), vec![ip])]);
+        assert!(set_default_resolver(StubResolver::in_memory(map)));
+        assert!(!set_default_resolver(StubResolver::in_memory(
+            HashMap::new()
+        )));
+
+        let addrs: Vec<(IpAddr, Duration)> = block_on(lookup("example.test")).unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].0, ip);
+        assert!(matches!(
+            block_on(lookup::<Vec<_>>("missing.test")),
+            Err(Error::NxDomain {})
+        ));
     }
 }
@@ -10,3 +10,5 @@
 
 pub(crate) mod tcp;
 pub(crate) mod udp;
+#[cfg(unix)]
+pub(crate) mod unix;
@@ -8,46 +8,128 @@
 // If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 
+use std::collections::VecDeque;
 use std::rc::Rc;
 use std::{net::SocketAddr, time::Duration};
 
-use domain::base::Message;
+use domain::{
+    base::{iana::OptRcode, name::ParsedName, Message, Rtype},
+    rdata::AllRecordData,
+};
 use monoio::{
-    io::{AsyncReadRentExt, AsyncWriteRentExt},
+    io::{stream::Stream, AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, AsyncWriteRentExt},
     net::TcpStream,
 };
 
-use crate::{addr::IpAddresses, errors::Error};
+use crate::{answer::ZoneRecord, errors::Error};
 
-/// Query a nameserver for the given question, using the TCP protocol.
+/// Query a nameserver for the given questions, using the TCP protocol.
+///
+/// All the queries are written on a single connection before reading the responses, which may
+/// come back in any order (RFC 7766). The queries left unanswered when the nameserver closes the
+/// connection are sent again on a new one, up to `attempts` connections, each bounded by
+/// `timeout_duration`. The responses are returned in the order of the queries, `None` for the
+/// ones that were not answered, along with the number of times each query was sent.
 #[cold]
-
 pub(crate) async fn query(
-    id: u16,
-    query: Rc<Vec<u8>>,
+    queries: Vec<(u16, Rc<Vec<u8>>)>,
     nameserver: &SocketAddr,
-    _attempts: u8,
-    _timeout_duration: Duration,
+    attempts: u8,
+    timeout_duration: Duration,
     _udp_payload_size: u16,
-) -> Result<Option<IpAddresses>, Error> {
-    if query.len() > u16::MAX as usize {
+) -> Result<Vec<(Option<Message<Vec<u8>>>, u8)>, Error> {
+    if queries
+        .iter()
+        .any(|(_, query)| query.len() > u16::MAX as usize)
+    {
         return Err(Error::QueryTooLarge {});
     }
 
-    // Open the socket to the server.
-    let mut socket = TcpStream::connect(nameserver).await?;
+    let mut responses: Vec<Option<Message<Vec<u8>>>> = queries.iter().map(|_| None).collect();
+    let mut sent = vec![0u8; queries.len()];
+    let mut error = None;
+    for _ in 0..attempts.max(1) {
+        let pending: Vec<usize> = (0..queries.len())
+            .filter(|idx| responses[*idx].is_none())
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+
+        let batch: Vec<_> = pending.iter().map(|idx| queries[*idx].clone()).collect();
+        let mut answered: Vec<Option<Message<Vec<u8>>>> = batch.iter().map(|_| None).collect();
+        let result = monoio::time::timeout(timeout_duration, async {
+            // Open the socket to the server.
+            let mut socket = TcpStream::connect(nameserver).await?;
+            exchange(&mut socket, &batch, &mut answered).await
+        })
+        .await;
+        for (idx, response) in pending.into_iter().zip(answered) {
+            sent[idx] = sent[idx].saturating_add(1);
+            responses[idx] = response;
+        }
+        match result {
+            Ok(Ok(())) => error = None,
+            Ok(Err(err)) => error = Some(err),
+            Err(elapsed) => error = Some(Error::from(elapsed)),
+        }
+    }
+
+    // If nothing was answered, tell why.
+    match error {
+        Some(err) if responses.iter().all(Option::is_none) => Err(err),
+        _ => Ok(responses.into_iter().zip(sent).collect()),
+    }
+}
 
+/// Write the queries to the stream, then read their responses into `responses`, in the order of
+/// the queries, using the framing of the TCP protocol.
+///
+/// The responses read so far are kept when the stream fails, or the future is dropped, before
+/// all the queries are answered.
+pub(crate) async fn exchange(
+    socket: &mut (impl AsyncReadRent + AsyncWriteRent),
+    queries: &[(u16, Rc<Vec<u8>>)],
+    responses: &mut [Option<Message<Vec<u8>>>],
+) -> Result<(), Error> {
+    for (_, query) in queries.iter() {
+        write_query(socket, query.clone()).await?;
+    }
+
+    let mut unanswered = responses.iter().filter(|x| x.is_none()).count();
+    while unanswered > 0 {
+        let message = read_response(socket).await?;
+        // A message without the QR bit is a query, not a response.
+        if !message.header().qr() {
+            continue;
+        }
+        // Match the response to its query by the ID.
+        let id = message.header().id();
+        if let Some(idx) = queries.iter().position(|(x, _)| *x == id) {
+            if responses[idx].is_none() {
+                unanswered -= 1;
+            }
+            responses[idx] = Some(message);
+        }
+    }
+    Ok(())
+}
+
+async fn write_query(socket: &mut impl AsyncWriteRent, query: Rc<Vec<u8>>) -> Result<(), Error> {
     // Write the length of the query.
     let len_bytes = Vec::from((query.len() as u16).to_be_bytes());
-    let (result, mut len_bytes) = socket.write_all(len_bytes).await;
+    let (result, _) = socket.write_all(len_bytes).await;
     let _ = result?;
 
     // Write the query.
     let (result, _) = socket.write_all(query).await;
     let _ = result?;
+    Ok(())
+}
 
+async fn read_response(socket: &mut impl AsyncReadRent) -> Result<Message<Vec<u8>>, Error> {
     // Read the length of the response.
-    len_bytes.clear();
+    let len_bytes = vec![0u8; 2];
     let (result, len_bytes) = socket.read_exact(len_bytes).await;
     let _ = result?;
 
@@ -58,11 +140,199 @@ pub(crate) async fn query(
     let (result, buf) = socket.read_exact(buf).await;
     let _ = result?;
 
-    let message = Message::from_octets(buf)?;
-    // Check the ID.
-    if message.header().id() != id {
-        return Ok(None);
+    Ok(Message::from_octets(buf)?)
+}
+
+/// Start a zone transfer (AXFR) from the nameserver, the records are then read from the
+/// returned stream as the messages arrive.
+#[cold]
+pub(crate) async fn transfer(
+    id: u16,
+    query: Rc<Vec<u8>>,
+    nameserver: &SocketAddr,
+) -> Result<Transfer, Error> {
+    if query.len() > u16::MAX as usize {
+        return Err(Error::QueryTooLarge {});
+    }
+
+    let mut socket = TcpStream::connect(nameserver).await?;
+    write_query(&mut socket, query).await?;
+    Ok(Transfer {
+        socket,
+        id,
+        records: VecDeque::new(),
+        soas: 0,
+        done: false,
+    })
+}
+
+/// The records of a zone transfer, which spans as many messages as the nameserver likes, from
+/// the SOA record of the zone to the same SOA record again (RFC 5936, section 2.2).
+pub(crate) struct Transfer {
+    socket: TcpStream,
+    id: u16,
+    records: VecDeque<ZoneRecord>,
+    soas: usize,
+    done: bool,
+}
+
+impl Transfer {
+    fn push_records(&mut self, message: &Message<Vec<u8>>) -> Result<(), Error> {
+        if message.header().id() != self.id {
+            return Err(Error::InvalidMessageID {
+                expected: self.id,
+                found: message.header().id(),
+            });
+        }
+        let rcode = crate::addr::rcode(message);
+        if rcode != OptRcode::NOERROR {
+            return Err(Error::TransferFailed {
+                rcode: rcode.to_int(),
+            });
+        }
+
+        for record in message.answer()? {
+            let record = record?;
+            if record.rtype() == Rtype::SOA {
+                self.soas += 1;
+            } else if self.soas == 0 {
+                // The transfer must start with the SOA record.
+                return Err(Error::MalformedTransfer {});
+            }
+            let Some(record) = record.into_record::<AllRecordData<&[u8], ParsedName<&[u8]>>>()?
+            else {
+                continue;
+            };
+            self.records.push_back(ZoneRecord {
+                owner: record.owner().to_string(),
+                rtype: record.rtype(),
+                class: record.class(),
+                ttl: record.ttl().into_duration(),
+                data: record.data().to_string(),
+            });
+            if self.soas == 2 {
+                self.done = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Stream for Transfer {
+    type Item = Result<ZoneRecord, Error>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.pop_front() {
+                return Some(Ok(record));
+            }
+            if self.done {
+                return None;
+            }
+
+            let result = match read_response(&mut self.socket).await {
+                Ok(message) => self.push_records(&message),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use monoio::net::TcpListener;
+
+    use super::*;
+    use crate::testing::block_on;
+
+    /// A query with the given ID and no question, which is enough for the exchange.
+    fn query_with_id(id: u16) -> (u16, Rc<Vec<u8>>) {
+        let mut query = vec![0; 12];
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        (id, Rc::new(query))
+    }
+
+    /// Serve on a local port, reading `queries` queries on each connection in turn and answering
+    /// the first `answers` of them before closing it, or holding it open if there are none.
+    /// Returns the address to connect to, and the number of connections accepted.
+    fn serve(connections: Vec<(usize, usize)>) -> (SocketAddr, Rc<Cell<usize>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Rc::new(Cell::new(0));
+        let counter = accepted.clone();
+        monoio::spawn(async move {
+            for (queries, answers) in connections {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.set(counter.get() + 1);
+                let mut received = Vec::new();
+                for _ in 0..queries {
+                    received.push(read_response(&mut stream).await.unwrap());
+                }
+                if answers == 0 {
+                    std::future::pending::<()>().await;
+                }
+                for message in received.into_iter().take(answers) {
+                    // Turn the query into its response.
+                    let mut response = message.into_octets();
+                    response[2] |= 0x80;
+                    write_query(&mut stream, Rc::new(response)).await.unwrap();
+                }
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[test]
+    fn queries_share_one_connection() {
+        block_on(async {
+            let (addr, accepted) = serve(vec![(2, 2)]);
+            let queries = vec![query_with_id(1), query_with_id(2)];
+            let responses = query(queries, &addr, 2, Duration::from_secs(5), 512)
+                .await
+                .unwrap();
+            assert_eq!(accepted.get(), 1);
+            for ((response, sent), id) in responses.into_iter().zip([1, 2]) {
+                assert_eq!(response.unwrap().header().id(), id);
+                assert_eq!(sent, 1);
+            }
+        });
+    }
+
+    #[test]
+    fn unanswered_queries_are_sent_again() {
+        block_on(async {
+            // The first connection is closed once the first query is answered.
+            let (addr, accepted) = serve(vec![(2, 1), (1, 1)]);
+            let queries = vec![query_with_id(1), query_with_id(2)];
+            let responses = query(queries, &addr, 2, Duration::from_secs(5), 512)
+                .await
+                .unwrap();
+            assert_eq!(accepted.get(), 2);
+            let sent: Vec<u8> = responses.iter().map(|(_, sent)| *sent).collect();
+            assert_eq!(sent, [1, 2]);
+            assert!(responses.iter().all(|(response, _)| response.is_some()));
+        });
+    }
+
+    #[test]
+    fn silent_nameserver_times_out() {
+        block_on(async {
+            let (addr, _) = serve(vec![(1, 0)]);
+            let result = query(
+                vec![query_with_id(1)],
+                &addr,
+                1,
+                Duration::from_millis(100),
+                512,
+            );
+            assert!(matches!(result.await, Err(Error::TimeoutError { .. })));
+        });
     }
-    let ip_addresses = IpAddresses::from(message);
-    Ok(Some(ip_addresses))
 }
@@ -11,17 +11,22 @@
 use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     rc::Rc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use domain::base::Message;
 use monoio::{io::Canceller, net::udp::UdpSocket};
 
-use crate::{addr::IpAddresses, errors::Error};
+use crate::errors::Error;
+
+/// Room left in the receive buffer past the advertised payload size, so that a nameserver ignoring
+/// it is noticed rather than having its response silently cut off.
+const HEADROOM: usize = 512;
 
 /// Query a nameserver for the given question, using the UDP protocol.
 ///
-/// Returns `None` if the UDP query failed and TCP should be used instead.
+/// Returns `None` if the response was truncated, or too large for the receive buffer, and TCP
+/// should be used instead, along with the number of attempts made.
 pub(crate) async fn query(
     id: u16,
     query: Rc<Vec<u8>>,
@@ -29,20 +34,19 @@ pub(crate) async fn query(
     attempts: u8,
     timeout_duration: Duration,
     udp_payload_size: u16,
-) -> Result<Option<IpAddresses>, Error> {
+) -> Result<(Option<Message<Vec<u8>>>, u8), Error> {
     // Write the query to the nameserver address.
     let bind_address: SocketAddr = match nameserver.ip() {
         std::net::IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
         std::net::IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
     };
     let socket = UdpSocket::bind(bind_address)?;
+    // A connected socket gets told when the nameserver is not listening, rather than waiting
+    // for a response that will never come.
+    socket.connect(*nameserver).await?;
 
-    async fn send(
-        socket: &UdpSocket,
-        buf: Rc<Vec<u8>>,
-        server: SocketAddr,
-    ) -> Result<usize, std::io::Error> {
-        socket.send_to(buf, server).await.0
+    async fn send(socket: &UdpSocket, buf: Rc<Vec<u8>>) -> Result<usize, std::io::Error> {
+        socket.send(buf).await.0
     }
 
     async fn recv(
@@ -60,40 +64,75 @@ pub(crate) async fn query(
         socket.cancelable_recv(buf, handle).await
     }
 
+    let capacity = udp_payload_size as usize + HEADROOM;
     for idx in 1..=attempts {
-        let buf = Vec::with_capacity(udp_payload_size as usize);
-        let result = send(&socket, query.clone(), *nameserver).await;
+        let result = send(&socket, query.clone()).await;
         if let Err(err) = result {
+            if refused(&err) {
+                return Err(Error::ConnectionRefused {
+                    nameserver: *nameserver,
+                });
+            }
             if idx < attempts {
                 continue;
             }
             return Err(Error::from(err));
         }
-        let (result, buf) = recv(&socket, buf, timeout_duration).await;
-        if let Err(err) = result {
-            if idx < attempts {
+
+        // Anything but the response to the query is ignored, and we keep waiting for the response
+        // until the attempt times out.
+        let deadline = Instant::now() + timeout_duration;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let buf = Vec::with_capacity(capacity);
+            let (result, buf) = recv(&socket, buf, remaining).await;
+            if let Err(err) = result {
+                if refused(&err) {
+                    return Err(Error::ConnectionRefused {
+                        nameserver: *nameserver,
+                    });
+                }
+                if idx < attempts {
+                    break;
+                }
+                return Err(Error::from(err));
+            };
+
+            // The datagram filled the buffer, it was likely cut short. The header is still whole,
+            // so it is only acted upon once the datagram is known to be our response.
+            let overflowed = buf.len() == buf.capacity();
+            let Ok(message) = Message::from_octets(buf) else {
+                continue;
+            };
+            let header = message.header();
+
+            // Check the ID.
+            if header.id() != id {
                 continue;
             }
-            return Err(Error::from(err));
-        };
 
-        let message = Message::from_octets(buf)?;
-        let header = message.header();
+            // A message without the QR bit is a query, not the response we are waiting for.
+            if !header.qr() {
+                continue;
+            }
 
-        // Check the ID.
-        if header.id() != id {
-            continue;
-        }
+            // Check truncation
+            if overflowed || header.tc() {
+                return Ok((None, idx));
+            }
 
-        // Check truncation
-        if header.tc() {
-            return Ok(None);
+            return Ok((Some(message), idx));
         }
-
-        let ip_addresses = IpAddresses::from(message);
-        return Ok(Some(ip_addresses));
     }
 
     // We did not receive a response.
-    Ok(None)
+    Err(Error::NoResponse {})
+}
+
+/// Whether the nameserver told us it is not listening, there is no point in trying again.
+fn refused(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::ConnectionRefused
 }
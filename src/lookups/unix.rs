@@ -0,0 +1,41 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::{path::Path, rc::Rc};
+
+use domain::base::Message;
+use monoio::net::UnixStream;
+
+use crate::errors::Error;
+
+/// Query a local proxy for the given questions over a Unix domain socket, using the framing of
+/// the TCP protocol.
+#[cold]
+pub(crate) async fn query(
+    queries: &[(u16, Rc<Vec<u8>>)],
+    path: &Path,
+) -> Result<Vec<Option<Message<Vec<u8>>>>, Error> {
+    if queries
+        .iter()
+        .any(|(_, query)| query.len() > u16::MAX as usize)
+    {
+        return Err(Error::QueryTooLarge {});
+    }
+
+    // Open the socket to the proxy.
+    let mut socket = UnixStream::connect(path).await?;
+    let mut responses: Vec<_> = queries.iter().map(|_| None).collect();
+    match super::tcp::exchange(&mut socket, queries, &mut responses).await {
+        // The proxy closed the connection, the queries left are not answered.
+        Err(_) if responses.iter().any(Option::is_some) => Ok(responses),
+        Err(err) => Err(err),
+        Ok(()) => Ok(responses),
+    }
+}
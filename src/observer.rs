@@ -0,0 +1,35 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::net::SocketAddr;
+
+/// Gets told about the events of the lookups, see
+/// [`StubResolver::with_observer`](crate::StubResolver::with_observer).
+///
+/// All the methods do nothing by default.
+pub trait Observer {
+    /// A query for `name` is sent to `nameserver` over TCP rather than UDP.
+    fn on_tcp_fallback(&self, name: &str, nameserver: &SocketAddr, reason: TcpFallbackReason) {
+        let _ = (name, nameserver, reason);
+    }
+}
+
+/// Why a query is sent over TCP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpFallbackReason {
+    /// The query is larger than the UDP payload size.
+    OversizedQuery,
+    /// The response over UDP was truncated.
+    Truncated,
+    /// No response came over UDP.
+    UdpFailed,
+    /// The transport or the record type calls for TCP.
+    Forced,
+}
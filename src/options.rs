@@ -0,0 +1,67 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{net::IpAddr, time::Duration};
+
+/// Settings for a single lookup, overriding the ones of the resolver, see
+/// [`StubResolver::lookup_opts`](crate::StubResolver::lookup_opts).
+///
+/// The settings left as `None` keep the behavior of the resolver.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LookupOptions {
+    /// Whether to try the name with the search domains, `false` uses the name as is.
+    pub search: Option<bool>,
+    /// The family of the addresses to look up, both if `None`.
+    pub family: Option<Family>,
+    /// How to send the queries to the nameservers.
+    pub transport: Option<Transport>,
+    /// How long to wait for each response.
+    pub timeout: Option<Duration>,
+    /// The EDNS options to add to the queries, as pairs of option code and data, for the ones
+    /// not supported by the resolver. They are left out if EDNS is disabled.
+    pub edns_options: Vec<(u16, Vec<u8>)>,
+    /// Sets the DO bit, asking for the DNSSEC records along with the answer (RFC 3225). It is
+    /// left out if EDNS is disabled.
+    pub dnssec_ok: bool,
+    /// Sets the CD bit, asking the nameserver not to validate the answer (RFC 4035, section 3.2.2).
+    pub checking_disabled: bool,
+}
+
+/// An address family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    pub(crate) fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            Self::Ipv4 => ip.is_ipv4(),
+            Self::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// The protocol used to send the queries to a nameserver.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// UDP, falling back to TCP for the responses that don't fit in a datagram.
+    #[default]
+    Udp,
+    /// TCP only.
+    Tcp,
+    /// A Unix domain socket to a local proxy speaking the TCP framing, which is asked instead of
+    /// the nameservers.
+    #[cfg(unix)]
+    UnixSocket { path: PathBuf },
+}
@@ -8,14 +8,17 @@
 // If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use bstr::ByteSlice;
 use monoio::fs::read;
 
 use crate::errors::Error;
-use crate::{HostEntry, StubResolver};
+use crate::{HostEntry, IpPolicy, Source, StubResolver, Transport};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 const NAMESERVER: &[u8] = "nameserver".as_bytes();
@@ -25,43 +28,84 @@ const OPTION_TIMEOUT: &[u8] = "timeout".as_bytes();
 const OPTION_ATTEMPTS: &[u8] = "attempts".as_bytes();
 const OPTION_ROTATE: &[u8] = "rotate".as_bytes();
 const OPTION_EDNS0: &[u8] = "edns0".as_bytes();
+const OPTION_TRUST_AD: &[u8] = "trust-ad".as_bytes();
 const SEARCH: &[u8] = "search".as_bytes();
+const SEARCH_MAX_DOMAINS: usize = 6;
+const SEARCH_MAX_LEN: usize = 256;
+const NDOTS_MAX: u8 = 15;
+const TIMEOUT_MAX: u8 = 30;
+const ATTEMPTS_MAX: u8 = 5;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
 const DEFAULT_NAMESERVER_IPV4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53);
 const DEFAULT_NAMESERVER_IPV6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 53);
+const DEFAULT_HOSTS_TTL: Duration = Duration::from_secs(i32::MAX as u64);
+
+impl HostEntry {
+    /// Parse a single line of a hosts file, blank and comment lines give `None`.
+    pub fn from_line(line: &str) -> Result<Option<Self>, Error> {
+        Self::parse(line.as_bytes())
+    }
+
+    fn parse(line: &[u8]) -> Result<Option<Self>, Error> {
+        let mut it = line.fields().take_while(|field| !field.starts_with(b"#"));
+        if let Some(ip) = it.next() {
+            let ip = ip.to_str()?;
+            let ip = IpAddr::from_str(ip)?;
+            let hosts = it
+                .map(|host| String::from_utf8(host.to_owned()))
+                .filter_map(|host| host.ok());
+            Ok(Some(HostEntry::new(ip, hosts)))
+        } else {
+            Ok(None)
+        }
+    }
+}
 
 impl StubResolver {
     async fn parse_hosts(&mut self) -> Result<(), Error> {
         let content = read("/etc/hosts").await?;
         for line in content.lines() {
-            let mut it = line.fields().take_while(|field| !field.starts_with(b"#"));
-            if let Some(ip) = it.next() {
-                let ip = ip.to_str()?;
-                let ip = IpAddr::from_str(ip)?;
-                let hosts = it
-                    .map(|host| String::from_utf8(host.to_owned()))
-                    .filter_map(|host| host.ok());
-                let entry = HostEntry::new(ip, hosts);
+            if let Some(entry) = HostEntry::parse(line)? {
                 self.entries.push(entry);
-            } else {
-                continue;
-            };
+            }
         }
         Ok(())
     }
 
-    async fn parse_resolv(&mut self) -> Result<(), Error> {
-        let content = read("/etc/resolv.conf").await?;
-        for line in content.lines() {
+    async fn parse_resolv(&mut self, path: &Path) -> Result<(), Error> {
+        let content = read(path).await.map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Error::ResolvConfNotFound {},
+            _ => Error::ResolvConfUnreadable { source: err },
+        })?;
+        self.parse_resolv_conf(&content)
+    }
+
+    fn parse_resolv_conf(&mut self, content: &[u8]) -> Result<(), Error> {
+        for (idx, line) in content.lines().enumerate() {
+            let malformed = || Error::ResolvConfParseError { line: idx + 1 };
             let mut it = line.fields().take_while(|field| !field.starts_with(b"#"));
             match it.next() {
+                // Only the last line counts, as with glibc.
                 Some(SEARCH) => {
                     let it = it.filter_map(|x| x.to_str().ok()).map(|x| x.to_owned());
-                    self.search = it.collect();
+                    self.search = Vec::with_capacity(SEARCH_MAX_DOMAINS);
+                    let mut len = 0;
+                    for domain in it.take(SEARCH_MAX_DOMAINS) {
+                        // The domains are counted with the space separating them.
+                        len += domain.len() + 1;
+                        if len > SEARCH_MAX_LEN + 1 {
+                            break;
+                        }
+                        self.search.push(domain);
+                    }
                 }
                 Some(NAMESERVER) => {
                     if let Some(ip) = it.next() {
-                        let ip = ip.to_str()?;
-                        let ip = IpAddr::from_str(ip)?;
+                        let ip = ip
+                            .to_str()
+                            .ok()
+                            .and_then(|ip| IpAddr::from_str(ip).ok())
+                            .ok_or_else(malformed)?;
                         let addr = SocketAddr::new(ip, 53);
                         self.nameservers.push(addr);
                     }
@@ -70,13 +114,30 @@ impl StubResolver {
                     for field in it {
                         if OPTION_EDNS0 == field {
                             self.udp_payload_size = 1232;
+                        } else if OPTION_TRUST_AD == field {
+                            self.trust_ad = true;
                         } else if let Some((key, value)) = field.split_once_str(":") {
+                            // As with glibc, the values are capped, and the ones that are not
+                            // numbers are ignored. A timeout or a number of attempts of 0 is
+                            // taken as 1.
+                            let number = |max: u8| {
+                                let value = u64::from_str(value.to_str().ok()?).ok()?;
+                                Some(value.min(max as u64) as u8)
+                            };
                             match key {
-                                OPTION_NDOTS => self.ndots = value[0],
+                                OPTION_NDOTS => {
+                                    self.ndots = number(NDOTS_MAX).unwrap_or(self.ndots)
+                                }
                                 OPTION_TIMEOUT => {
-                                    self.timeout = Duration::from_secs(value[0] as u64)
+                                    if let Some(secs) = number(TIMEOUT_MAX) {
+                                        self.timeout = Duration::from_secs(secs.max(1) as u64);
+                                    }
+                                }
+                                OPTION_ATTEMPTS => {
+                                    if let Some(attempts) = number(ATTEMPTS_MAX) {
+                                        self.attempts = attempts.max(1);
+                                    }
                                 }
-                                OPTION_ATTEMPTS => self.attempts = value[0],
                                 OPTION_ROTATE => self.rotate = true,
                                 _ => {}
                             }
@@ -86,15 +147,18 @@ impl StubResolver {
                 _ => {}
             }
         }
+        // Without any nameserver line, the nameserver of the local machine is used, as with glibc.
         if self.nameservers.is_empty() {
             self.nameservers.push(DEFAULT_NAMESERVER_IPV4);
             self.nameservers.push(DEFAULT_NAMESERVER_IPV6);
+            self.default_nameservers = true;
         }
         Ok(())
     }
 
-    pub async fn load() -> Result<Self, Error> {
-        let mut this = Self {
+    /// The resolver before any configuration is read.
+    fn unconfigured() -> Self {
+        Self {
             entries: Vec::default(),
             search: Vec::default(),
             nameservers: Vec::default(),
@@ -103,15 +167,223 @@ impl StubResolver {
             attempts: 2,
             rotate: false,
             udp_payload_size: 512,
-        };
+            trust_ad: false,
+            source_order: vec![Source::Overrides, Source::Hosts, Source::Network],
+            min_response_time: Duration::ZERO,
+            hosts_ttl: DEFAULT_HOSTS_TTL,
+            overrides: BTreeMap::default(),
+            parallel_search: false,
+            mdns_unicast: None,
+            padding: None,
+            edns_version: Some(0),
+            transport: Transport::Udp,
+            ip_policy: IpPolicy::default(),
+            tcp_rtypes: Vec::new(),
+            collect_timings: false,
+            nameserver_groups: Vec::new(),
+            nameserver_transports: BTreeMap::default(),
+            min_answers: 0,
+            observer: None,
+            in_memory: false,
+            name_rewriter: None,
+            sticky_nameserver: false,
+            sticky_nameservers: Arc::default(),
+            max_cname_chain: 8,
+            latencies: Arc::default(),
+            merge_sources: false,
+            family_nameservers: None,
+            default_nameservers: false,
+        }
+    }
+
+    /// A resolver configured from `/etc/hosts` and `/etc/resolv.conf`.
+    ///
+    /// Fails with [`Error::ResolvConfNotFound`] if `/etc/resolv.conf` does not exist,
+    /// [`Error::ResolvConfUnreadable`] if it can't be read, and [`Error::ResolvConfParseError`]
+    /// if a nameserver is not an address. Without any nameserver
+    /// line, the nameserver of the local machine is used, see
+    /// [`StubResolver::uses_default_nameservers`].
+    pub async fn load() -> Result<Self, Error> {
+        let mut this = Self::unconfigured();
         this.parse_hosts().await?;
-        this.parse_resolv().await?;
+        this.parse_resolv(Path::new(RESOLV_CONF)).await?;
         Ok(this)
     }
 
+    /// A resolver answering from the given map only, without reading the system configuration
+    /// nor sending any query, for testing the code using it. The names not in the map fail
+    /// with [`Error::NxDomain`].
+    ///
+    /// The names are matched as with [`StubResolver::override_name`], the addresses are given
+    /// the same TTL as the ones of `/etc/hosts`.
+    pub fn in_memory(map: HashMap<String, Vec<IpAddr>>) -> Self {
+        let mut this = Self::unconfigured();
+        this.source_order = vec![Source::Overrides];
+        this.in_memory = true;
+        for (name, addrs) in map {
+            this.override_name(&name, addrs, DEFAULT_HOSTS_TTL);
+        }
+        this
+    }
+
+    /// Same as [`StubResolver::load`], but leaves out the nameservers that don't answer a quick
+    /// query, so the lookups don't have to wait for them before failing over.
+    pub async fn load_probed() -> Result<Self, Error> {
+        let mut this = Self::load().await?;
+        this.probe_nameservers().await;
+        Ok(this)
+    }
+
+    /// Whether `/etc/resolv.conf` has no nameserver line, in which case the nameserver of the
+    /// local machine is used, as with glibc.
+    pub fn uses_default_nameservers(&self) -> bool {
+        self.default_nameservers
+    }
+
     pub async fn reload(&mut self) -> Result<(), Error> {
         let this = Self::load().await?;
-        *self = this;
+        // Only replace what comes from the system configuration, options set on the
+        // resolver itself are kept.
+        self.entries = this.entries;
+        self.search = this.search;
+        // The nameservers remembered for the names may no longer be among them.
+        if self.nameservers != this.nameservers {
+            self.sticky_nameservers.lock().unwrap().clear();
+        }
+        self.nameservers = this.nameservers;
+        self.timeout = this.timeout;
+        self.ndots = this.ndots;
+        self.attempts = this.attempts;
+        self.rotate = this.rotate;
+        self.udp_payload_size = this.udp_payload_size;
+        self.trust_ad = this.trust_ad;
+        self.default_nameservers = this.default_nameservers;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<StubResolver, Error> {
+        let mut this = StubResolver::unconfigured();
+        this.parse_resolv_conf(content.as_bytes())?;
+        Ok(this)
+    }
+
+    #[test]
+    fn host_entry_from_line() {
+        let entry = HostEntry::from_line("192.0.2.1 example.test alias.test # comment")
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.ip, IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(entry.canonical.as_deref(), Some("example.test"));
+        assert_eq!(entry.hosts.len(), 2);
+        assert!(entry.hosts.contains("alias.test"));
+
+        assert_eq!(HostEntry::from_line("").unwrap(), None);
+        assert_eq!(
+            HostEntry::from_line("# 192.0.2.1 example.test").unwrap(),
+            None
+        );
+        assert!(HostEntry::from_line("not-an-ip example.test").is_err());
+    }
+
+    #[test]
+    fn host_entry_display_round_trip() {
+        // The canonical name comes first, then the aliases in order.
+        let line = "2001:db8::1\tzz.test aa.test mm.test";
+        let entry = HostEntry::from_line(line).unwrap().unwrap();
+        assert_eq!(entry.to_string(), "2001:db8::1\tzz.test aa.test mm.test");
+        assert_eq!(
+            HostEntry::from_line(&entry.to_string()).unwrap(),
+            Some(entry)
+        );
+    }
+
+    #[test]
+    fn search_last_line_wins() {
+        let this = parse("search a.test b.test\nsearch c.test\nnameserver 192.0.2.53").unwrap();
+        assert_eq!(this.search, ["c.test"]);
+    }
+
+    #[test]
+    fn search_keeps_six_domains() {
+        let this = parse("search a b c d e f g h\nnameserver 192.0.2.53").unwrap();
+        assert_eq!(this.search, ["a", "b", "c", "d", "e", "f"]);
+    }
+
+    #[test]
+    fn search_keeps_256_characters() {
+        // Two domains of 127 characters and their separator make 255 characters, a third one
+        // goes past the limit.
+        let domain = "a".repeat(127);
+        let content = format!("search {domain} {domain} b\nnameserver 192.0.2.53");
+        let this = parse(&content).unwrap();
+        assert_eq!(this.search, [domain.clone(), domain]);
+    }
+
+    #[test]
+    fn options_are_numbers() {
+        let this = parse("options ndots:3 timeout:7 attempts:4\nnameserver 192.0.2.53").unwrap();
+        assert_eq!(this.ndots, 3);
+        assert_eq!(this.timeout, Duration::from_secs(7));
+        assert_eq!(this.attempts, 4);
+    }
+
+    #[test]
+    fn options_are_capped() {
+        let this =
+            parse("options ndots:16 timeout:31 attempts:256\nnameserver 192.0.2.53").unwrap();
+        assert_eq!(this.ndots, 15);
+        assert_eq!(this.timeout, Duration::from_secs(30));
+        assert_eq!(this.attempts, 5);
+
+        let this = parse("options ndots:0 timeout:0 attempts:0\nnameserver 192.0.2.53").unwrap();
+        assert_eq!(this.ndots, 0);
+        assert_eq!(this.timeout, Duration::from_secs(1));
+        assert_eq!(this.attempts, 1);
+    }
+
+    #[test]
+    fn malformed_options_are_ignored() {
+        let this = parse("options ndots: timeout:x attempts:-1\nnameserver 192.0.2.53").unwrap();
+        let unconfigured = StubResolver::unconfigured();
+        assert_eq!(this.ndots, unconfigured.ndots);
+        assert_eq!(this.timeout, unconfigured.timeout);
+        assert_eq!(this.attempts, unconfigured.attempts);
+    }
+
+    #[test]
+    fn missing_file() {
+        let mut this = StubResolver::unconfigured();
+        let path = Path::new("/nonexistent/resolv.conf");
+        assert!(matches!(
+            crate::testing::block_on(this.parse_resolv(path)),
+            Err(Error::ResolvConfNotFound {})
+        ));
+    }
+
+    #[test]
+    fn malformed_nameserver_is_an_error() {
+        assert!(matches!(
+            parse("search test\nnameserver not-an-ip"),
+            Err(Error::ResolvConfParseError { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn no_nameserver_falls_back_to_the_local_machine() {
+        let this = parse("search test\noptions ndots:2").unwrap();
+        assert!(this.uses_default_nameservers());
+        assert_eq!(
+            this.nameservers,
+            [DEFAULT_NAMESERVER_IPV4, DEFAULT_NAMESERVER_IPV6]
+        );
+
+        let this = parse("nameserver 192.0.2.53").unwrap();
+        assert!(!this.uses_default_nameservers());
+        assert_eq!(this.nameservers, [SocketAddr::from(([192, 0, 2, 53], 53))]);
+    }
+}
@@ -0,0 +1,226 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A range of addresses sharing the same prefix, as in `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// The prefix length is capped to the length of the address.
+    pub const fn new(addr: IpAddr, prefix_len: u8) -> Self {
+        Self { addr, prefix_len }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix(u32::from(net).into(), 32, self.prefix_len)
+                    == prefix(u32::from(*ip).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix(u128::from(net), 128, self.prefix_len)
+                    == prefix(u128::from(*ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The first `len` bits of an address `width` bits long.
+fn prefix(bits: u128, width: u32, len: u8) -> u128 {
+    let len = (len as u32).min(width);
+    if len == 0 {
+        0
+    } else {
+        bits >> (width - len)
+    }
+}
+
+/// Which addresses a lookup may return, see [`StubResolver::with_ip_policy`](crate::StubResolver::with_ip_policy).
+///
+/// An address is permitted if it is in one of the allowed ranges, or if there are none, and it
+/// is in none of the denied ranges. IPv4-mapped IPv6 addresses are checked as IPv4 addresses,
+/// and the IPv4 address in a NAT64 (`64:ff9b::/96`) or 6to4 (`2002::/16`) address must not be
+/// denied either.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpPolicy {
+    allowed: Vec<IpRange>,
+    denied: Vec<IpRange>,
+}
+
+impl IpPolicy {
+    /// A policy permitting every address.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A policy denying the loopback, private, shared, link-local, multicast, reserved,
+    /// benchmarking and unspecified ranges, for the lookups that must not reach the local
+    /// network.
+    pub fn reject_private() -> Self {
+        const V4: [(Ipv4Addr, u8); 11] = [
+            (Ipv4Addr::new(0, 0, 0, 0), 8),
+            (Ipv4Addr::new(10, 0, 0, 0), 8),
+            (Ipv4Addr::new(100, 64, 0, 0), 10),
+            (Ipv4Addr::new(127, 0, 0, 0), 8),
+            (Ipv4Addr::new(169, 254, 0, 0), 16),
+            (Ipv4Addr::new(172, 16, 0, 0), 12),
+            (Ipv4Addr::new(192, 0, 0, 0), 24),
+            (Ipv4Addr::new(192, 168, 0, 0), 16),
+            (Ipv4Addr::new(198, 18, 0, 0), 15),
+            (Ipv4Addr::new(224, 0, 0, 0), 4),
+            (Ipv4Addr::new(240, 0, 0, 0), 4),
+        ];
+        const V6: [(Ipv6Addr, u8); 6] = [
+            (Ipv6Addr::UNSPECIFIED, 128),
+            (Ipv6Addr::LOCALHOST, 128),
+            (Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+            (Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+            (Ipv6Addr::new(0xfec0, 0, 0, 0, 0, 0, 0, 0), 10),
+            (Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),
+        ];
+        let v4 = V4
+            .iter()
+            .map(|(ip, len)| IpRange::new(IpAddr::V4(*ip), *len));
+        let v6 = V6
+            .iter()
+            .map(|(ip, len)| IpRange::new(IpAddr::V6(*ip), *len));
+        Self {
+            allowed: Vec::new(),
+            denied: v4.chain(v6).collect(),
+        }
+    }
+
+    pub fn allow(mut self, range: IpRange) -> Self {
+        self.allowed.push(range);
+        self
+    }
+
+    pub fn deny(mut self, range: IpRange) -> Self {
+        self.denied.push(range);
+        self
+    }
+
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*ip, IpAddr::V4),
+            IpAddr::V4(_) => *ip,
+        };
+        let allowed = self.allowed.is_empty() || self.allowed.iter().any(|x| x.contains(&ip));
+        let denied = |ip: &IpAddr| self.denied.iter().any(|x| x.contains(ip));
+        allowed && !denied(&ip) && !embedded_ipv4(&ip).is_some_and(|x| denied(&IpAddr::V4(x)))
+    }
+}
+
+/// The IPv4 address a NAT64 or 6to4 address reaches.
+fn embedded_ipv4(ip: &IpAddr) -> Option<Ipv4Addr> {
+    const NAT64: IpRange = IpRange::new(
+        IpAddr::V6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0)),
+        96,
+    );
+    const SIX_TO_FOUR: IpRange =
+        IpRange::new(IpAddr::V6(Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0)), 16);
+    let IpAddr::V6(v6) = ip else {
+        return None;
+    };
+    let bits = u128::from(*v6);
+    if NAT64.contains(ip) {
+        Some(Ipv4Addr::from(bits as u32))
+    } else if SIX_TO_FOUR.contains(ip) {
+        Some(Ipv4Addr::from((bits >> 80) as u32))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn range_contains() {
+        let range = IpRange::new(ip("10.0.0.0"), 8);
+        assert!(range.contains(&ip("10.255.0.1")));
+        assert!(!range.contains(&ip("11.0.0.1")));
+        assert!(!range.contains(&ip("::a00:1")));
+
+        let range = IpRange::new(ip("2001:db8::"), 32);
+        assert!(range.contains(&ip("2001:db8:1::1")));
+        assert!(!range.contains(&ip("2001:db9::1")));
+
+        // A prefix of 0 contains the whole family, a prefix too long is capped.
+        assert!(IpRange::new(ip("0.0.0.0"), 0).contains(&ip("203.0.113.1")));
+        assert!(IpRange::new(ip("192.0.2.1"), 64).contains(&ip("192.0.2.1")));
+        assert!(!IpRange::new(ip("192.0.2.1"), 64).contains(&ip("192.0.2.2")));
+    }
+
+    #[test]
+    fn reject_private() {
+        let policy = IpPolicy::reject_private();
+        for denied in [
+            "0.0.0.0",
+            "10.1.2.3",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.251",
+            "240.0.0.1",
+            "255.255.255.255",
+            "ff02::fb",
+            "fec0::1",
+            "64:ff9b::a00:1",
+            "64:ff9b::7f00:1",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!policy.permits(&ip(denied)), "{denied}");
+        }
+        for permitted in [
+            "192.0.2.1",
+            "172.32.0.1",
+            "198.20.0.1",
+            "2001:db8::1",
+            "::ffff:192.0.2.1",
+            "64:ff9b::c000:201",
+            "2002:c000:201::1",
+        ] {
+            assert!(policy.permits(&ip(permitted)), "{permitted}");
+        }
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let policy = IpPolicy::new()
+            .allow(IpRange::new(ip("192.0.2.0"), 24))
+            .deny(IpRange::new(ip("192.0.2.128"), 25));
+        assert!(policy.permits(&ip("192.0.2.1")));
+        assert!(!policy.permits(&ip("192.0.2.200")));
+        assert!(!policy.permits(&ip("198.51.100.1")));
+        assert!(IpPolicy::new().permits(&ip("10.0.0.1")));
+    }
+}
@@ -20,6 +20,14 @@ impl StubResolver {
         self.entries
             .iter()
             .filter(move |entry| entry.hosts.contains(host.as_ref()))
-            .map(|entry| (entry.ip, Duration::ZERO))
+            .map(move |entry| (entry.ip, self.hosts_ttl))
+    }
+
+    /// The canonical hostname of the first entry with the given hostname.
+    pub(super) fn hosts_canonical_name(&self, host: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.hosts.contains(host))
+            .and_then(|entry| entry.canonical.as_deref())
     }
 }
@@ -8,12 +8,29 @@
 // If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
 //
 
-use std::{borrow::Borrow, net::IpAddr, str::FromStr, time::Duration};
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use crate::{errors::Error, StubResolver};
+use crate::{
+    answer::{Answer, Origin, ResolvedAddr},
+    errors::Error,
+    future::join_all,
+    LookupOptions, Source, StubResolver,
+};
 
 mod hosts;
+mod overrides;
+mod records;
 mod resolv;
+mod sticky;
+
+pub(crate) use overrides::override_key;
+pub(crate) use sticky::StickyNameservers;
 
 impl StubResolver {
     pub async fn lookup<'a, B>(
@@ -23,22 +40,501 @@ impl StubResolver {
     where
         B: FromIterator<(IpAddr, Duration)>,
     {
-        if let Ok(ip) = IpAddr::from_str(host.as_ref()) {
-            return Ok(std::iter::once((ip, Duration::ZERO)).collect());
+        self.lookup_ip_limited(host.as_ref(), usize::MAX).await
+    }
+
+    /// Same as [`StubResolver::lookup`], but stops collecting after `max` addresses.
+    pub async fn lookup_ip_limited<B>(&self, name: &str, max: usize) -> Result<B, Error>
+    where
+        B: FromIterator<(IpAddr, Duration)>,
+    {
+        let answer = self.resolve(name, max, &LookupOptions::default()).await?;
+        Ok(answer.addrs.into_iter().collect())
+    }
+
+    /// Same as [`StubResolver::lookup`], but only returns the first address, in the order of
+    /// the answer, failing with [`Error::NoAddresses`] if there is none.
+    pub async fn lookup_one(&self, name: &str) -> Result<IpAddr, Error> {
+        let addrs: Vec<(IpAddr, Duration)> = self.lookup_ip_limited(name, 1).await?;
+        match addrs.first() {
+            Some((ip, _)) => Ok(*ip),
+            None => Err(Error::NoAddresses {}),
+        }
+    }
+
+    /// Same as [`StubResolver::lookup`], but returns the `fallback` addresses if the lookup
+    /// fails or finds no address, for the clients with known backup endpoints.
+    pub async fn lookup_or(&self, name: &str, fallback: Vec<IpAddr>) -> Vec<IpAddr> {
+        match self.lookup::<Vec<(IpAddr, Duration)>>(name).await {
+            Ok(addrs) if !addrs.is_empty() => addrs.into_iter().map(|(ip, _)| ip).collect(),
+            _ => fallback,
         }
+    }
 
-        let mut count: u16 = 0;
-        let addrs = self
-            .query_hosts(host.as_ref())
-            .inspect(|_| count += 1)
+    /// Same as [`StubResolver::lookup`], but tries the name with `www.` prepended when it has no
+    /// address or does not exist, returning the name that resolved along with its addresses.
+    ///
+    /// A name already starting with `www.` is only tried as is.
+    pub async fn lookup_with_www_fallback(
+        &self,
+        name: &str,
+    ) -> Result<(String, Vec<(IpAddr, Duration)>), Error> {
+        let prefixed = name
+            .get(..4)
+            .is_some_and(|x| x.eq_ignore_ascii_case("www."));
+        match self.lookup_ip_limited(name, usize::MAX).await {
+            Ok(addrs) if !addrs.is_empty() => Ok((name.to_owned(), addrs)),
+            Ok(_) | Err(Error::NxDomain {}) | Err(Error::AllSearchFailed { .. }) if !prefixed => {
+                let www = format!("www.{name}");
+                let addrs = self.lookup_ip_limited(&www, usize::MAX).await?;
+                Ok((www, addrs))
+            }
+            Ok(addrs) => Ok((name.to_owned(), addrs)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Same as [`StubResolver::lookup`], but with where each address came from.
+    pub async fn lookup_resolved(&self, name: &str) -> Result<Vec<ResolvedAddr>, Error> {
+        let answer = self.lookup_detailed(name).await?;
+        let Some(origin) = answer.origin else {
+            return Ok(Vec::new());
+        };
+        let addrs = answer
+            .addrs
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (ip, ttl))| {
+                let origin = answer.origins.get(idx).copied().unwrap_or(origin);
+                ResolvedAddr { ip, ttl, origin }
+            })
             .collect();
+        Ok(addrs)
+    }
 
-        if count != 0 {
-            return Ok(addrs);
+    /// Same as [`StubResolver::lookup`], but with the details of how the answer was obtained.
+    pub async fn lookup_detailed(&self, name: &str) -> Result<Answer, Error> {
+        self.resolve(name, usize::MAX, &LookupOptions::default())
+            .await
+    }
+
+    /// Same as [`StubResolver::lookup`], but with some of the settings of the resolver
+    /// overridden for this lookup only.
+    pub async fn lookup_opts<B>(&self, name: &str, opts: LookupOptions) -> Result<B, Error>
+    where
+        B: FromIterator<(IpAddr, Duration)>,
+    {
+        let answer = self.resolve(name, usize::MAX, &opts).await?;
+        Ok(answer.addrs.into_iter().collect())
+    }
+
+    /// Same as [`StubResolver::lookup`], but with the given EDNS options, as pairs of option code
+    /// and data, added to the queries.
+    pub async fn lookup_with_edns_options<B>(
+        &self,
+        name: &str,
+        opts: &[(u16, Vec<u8>)],
+    ) -> Result<B, Error>
+    where
+        B: FromIterator<(IpAddr, Duration)>,
+    {
+        let opts = LookupOptions {
+            edns_options: opts.to_vec(),
+            ..Default::default()
+        };
+        self.lookup_opts(name, opts).await
+    }
+
+    async fn resolve(&self, name: &str, max: usize, opts: &LookupOptions) -> Result<Answer, Error> {
+        let start = Instant::now();
+        let result = self.consult_sources(name, max, opts).await;
+        let elapsed = start.elapsed();
+        let result = result.map(|mut answer| {
+            if self.collect_timings {
+                answer.timings.get_or_insert_with(Default::default).total = elapsed;
+            } else {
+                answer.timings = None;
+            }
+            answer
+        });
+        if elapsed < self.min_response_time {
+            monoio::time::sleep(self.min_response_time - elapsed).await;
         }
+        result
+    }
 
-        let addrs = self.query_resolv::<B>(host.as_ref()).await?;
+    async fn consult_sources(
+        &self,
+        name: &str,
+        max: usize,
+        opts: &LookupOptions,
+    ) -> Result<Answer, Error> {
+        if max == 0 {
+            return Ok(Answer::default());
+        }
 
-        Ok(addrs)
+        let wanted = |(ip, _): &(IpAddr, Duration)| opts.family.map_or(true, |x| x.matches(ip));
+        if let Ok(ip) = IpAddr::from_str(name) {
+            let addrs = Some((ip, Duration::ZERO)).into_iter().filter(wanted);
+            let addrs = self.permitted(addrs, max)?;
+            return Ok(Answer::from_addrs(addrs, Origin::Literal));
+        }
+
+        let mut local = Duration::ZERO;
+        let mut error = None;
+        let mut merged: Option<Answer> = None;
+        for source in &self.source_order {
+            let start = Instant::now();
+            let answer = match source {
+                Source::Overrides => self.query_overrides(name).map(|addrs| {
+                    let addrs = self.permitted(addrs.filter(wanted), max)?;
+                    Ok(Answer::from_addrs(addrs, Origin::Override))
+                }),
+                Source::Hosts => match self.permitted(self.query_hosts(name).filter(wanted), max) {
+                    Ok(addrs) if addrs.is_empty() => None,
+                    Ok(addrs) => Some(Ok(Answer {
+                        canonical_name: self.hosts_canonical_name(name).map(str::to_owned),
+                        ..Answer::from_addrs(addrs, Origin::HostsFile)
+                    })),
+                    Err(err) => Some(Err(err)),
+                },
+                Source::Network => Some(self.query_resolv(name, max, opts).await),
+            };
+            let answer = match answer {
+                Some(Ok(answer)) => Some(answer),
+                // The source knows the name, it is only its addresses that are denied.
+                Some(Err(err @ Error::BlockedAddress {})) if !self.merge_sources => {
+                    return Err(err)
+                }
+                Some(Err(err)) => {
+                    error = Some(err);
+                    None
+                }
+                None => None,
+            };
+            if *source != Source::Network {
+                local += start.elapsed();
+            }
+            match (answer, &mut merged) {
+                (Some(answer), Some(merged)) => {
+                    if merged.addrs.is_empty() && !answer.addrs.is_empty() {
+                        merged.origin = answer.origin;
+                        merged.nodata = false;
+                    }
+                    for (ip, ttl) in answer.addrs {
+                        if merged.addrs.len() < max && !merged.addrs.iter().any(|x| x.0 == ip) {
+                            merged.addrs.push((ip, ttl));
+                            merged.origins.extend(answer.origin);
+                        }
+                    }
+                    merged.questions.extend(answer.questions);
+                    merged.records.extend(answer.records);
+                    merged.transaction_ids.extend(answer.transaction_ids);
+                    merged.query_bytes += answer.query_bytes;
+                    merged.response_bytes += answer.response_bytes;
+                }
+                (Some(mut answer), None) if self.merge_sources => {
+                    answer.origins = answer
+                        .origin
+                        .map(|origin| vec![origin; answer.addrs.len()])
+                        .unwrap_or_default();
+                    merged = Some(answer);
+                }
+                (Some(mut answer), None) => {
+                    answer.timings.get_or_insert_with(Default::default).local = local;
+                    return Ok(answer);
+                }
+                (None, _) => {}
+            }
+        }
+
+        if let Some(mut answer) = merged {
+            answer.timings.get_or_insert_with(Default::default).local = local;
+            return Ok(answer);
+        }
+        match error {
+            Some(err) => Err(err),
+            None if self.in_memory => Err(Error::NxDomain {}),
+            None => Ok(Answer::default()),
+        }
+    }
+
+    /// Keep up to `max` of the addresses permitted by the IP policy, failing with
+    /// [`Error::BlockedAddress`] if there are some but none is permitted.
+    ///
+    /// The policy comes before the cap, so that denied addresses don't take the place of
+    /// permitted ones.
+    pub(super) fn permitted(
+        &self,
+        addrs: impl Iterator<Item = (IpAddr, Duration)>,
+        max: usize,
+    ) -> Result<Vec<(IpAddr, Duration)>, Error> {
+        let mut any = false;
+        let permitted: Vec<_> = addrs
+            .inspect(|_| any = true)
+            .filter(|(ip, _)| self.ip_policy.permits(ip))
+            .take(max)
+            .collect();
+        if any && permitted.is_empty() {
+            return Err(Error::BlockedAddress {});
+        }
+        Ok(permitted)
+    }
+
+    /// Looks up all the names concurrently, giving up on the ones not resolved within `timeout`.
+    ///
+    /// The results are in the order of the names, the lookups cut short fail with
+    /// [`Error::TimeoutError`] while the others keep their result.
+    pub async fn lookup_many<B>(&self, names: &[&str], timeout: Duration) -> Vec<Result<B, Error>>
+    where
+        B: FromIterator<(IpAddr, Duration)>,
+    {
+        let lookups = names.iter().map(|name| async move {
+            match monoio::time::timeout(timeout, self.lookup(*name)).await {
+                Ok(result) => result,
+                Err(elapsed) => Err(Error::from(elapsed)),
+            }
+        });
+        join_all(lookups).await
+    }
+
+    /// Same as [`StubResolver::lookup`], but with the IPv4 and IPv6 addresses apart, each in the
+    /// order of the answer.
+    pub async fn lookup_ip_split(
+        &self,
+        name: &str,
+    ) -> Result<(Vec<(Ipv4Addr, Duration)>, Vec<(Ipv6Addr, Duration)>), Error> {
+        let addrs: Vec<(IpAddr, Duration)> = self.lookup(name).await?;
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for (ip, ttl) in addrs {
+            match ip {
+                IpAddr::V4(ip) => v4.push((ip, ttl)),
+                IpAddr::V6(ip) => v6.push((ip, ttl)),
+            }
+        }
+        Ok((v4, v6))
+    }
+
+    /// Same as [`StubResolver::lookup`], but with the addresses grouped by their TTL, shortest first.
+    pub async fn lookup_ttl_grouped(
+        &self,
+        name: &str,
+    ) -> Result<Vec<(Duration, Vec<IpAddr>)>, Error> {
+        let addrs: Vec<(IpAddr, Duration)> = self.lookup(name).await?;
+        let mut groups = BTreeMap::<Duration, Vec<IpAddr>>::new();
+        for (ip, ttl) in addrs {
+            groups.entry(ttl).or_default().push(ip);
+        }
+        Ok(groups.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use domain::{
+        base::{iana::Class, MessageBuilder, Name, Record, Ttl},
+        rdata::A,
+    };
+
+    use super::*;
+    use crate::{testing::block_on, HostEntry, IpPolicy};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// A resolver answering only from the given names and addresses.
+    fn in_memory(names: &[(&str, &[&str])]) -> StubResolver {
+        let map = names
+            .iter()
+            .map(|(name, ips)| (name.to_string(), ips.iter().map(|x| ip(x)).collect()))
+            .collect();
+        StubResolver::in_memory(map)
+    }
+
+    fn addrs(ips: &[&str]) -> Vec<(IpAddr, Duration)> {
+        ips.iter()
+            .map(|ip| (ip.parse().unwrap(), Duration::from_secs(60)))
+            .collect()
+    }
+
+    #[test]
+    fn permitted_filters_before_capping() {
+        let resolver =
+            StubResolver::in_memory(Default::default()).with_ip_policy(IpPolicy::reject_private());
+        let permitted = resolver
+            .permitted(
+                addrs(&["10.0.0.1", "127.0.0.1", "192.0.2.1", "192.0.2.2"]).into_iter(),
+                1,
+            )
+            .unwrap();
+        assert_eq!(permitted, addrs(&["192.0.2.1"]));
+    }
+
+    #[test]
+    fn permitted_fails_when_all_are_denied() {
+        let resolver =
+            StubResolver::in_memory(Default::default()).with_ip_policy(IpPolicy::reject_private());
+        assert!(matches!(
+            resolver.permitted(addrs(&["10.0.0.1", "::1"]).into_iter(), 2),
+            Err(Error::BlockedAddress {})
+        ));
+        // No address at all is not blocked.
+        assert!(resolver
+            .permitted(std::iter::empty(), 2)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// A resolver with `both.test` in its overrides and in its hosts entries.
+    fn both_sources(merge_sources: bool) -> StubResolver {
+        let overridden = IpAddr::from([192, 0, 2, 1]);
+        let map = HashMap::from([("both.test".to_owned(), vec![overridden])]);
+        let mut resolver = StubResolver::in_memory(map)
+            .with_source_order(vec![Source::Overrides, Source::Hosts])
+            .with_merge_sources(merge_sources);
+        let hosts = ["both.test".to_owned()].into_iter();
+        resolver
+            .entries
+            .push(HostEntry::new(IpAddr::from([192, 0, 2, 2]), hosts));
+        resolver
+    }
+
+    #[test]
+    fn first_source_wins() {
+        let resolver = both_sources(false);
+        let addrs = block_on(resolver.lookup_resolved("both.test")).unwrap();
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].ip, IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(addrs[0].origin, Origin::Override);
+    }
+
+    #[test]
+    fn merged_sources_keep_their_origins() {
+        let resolver = both_sources(true);
+        let addrs = block_on(resolver.lookup_resolved("both.test")).unwrap();
+        let found: Vec<_> = addrs.iter().map(|x| (x.ip, x.origin)).collect();
+        assert_eq!(
+            found,
+            [
+                (IpAddr::from([192, 0, 2, 1]), Origin::Override),
+                (IpAddr::from([192, 0, 2, 2]), Origin::HostsFile),
+            ]
+        );
+    }
+
+    #[test]
+    fn many_addresses_are_capped() {
+        let owner = Name::from_octets(b"\x07example\x04test\x00".to_vec()).unwrap();
+        let mut message = MessageBuilder::new_vec();
+        message.header_mut().set_qr(true);
+        let mut message = message.answer();
+        for idx in 1..=5 {
+            let data = A::new(Ipv4Addr::new(192, 0, 2, idx));
+            let record = Record::new(owner.clone(), Class::IN, Ttl::from_secs(60), data);
+            message.push(record).unwrap();
+        }
+        let response = crate::addr::IpAddresses::from(message.into_message());
+        assert_eq!(response.iter().count(), 5);
+
+        let resolver = StubResolver::in_memory(Default::default());
+        let permitted = resolver.permitted(response.iter(), 2).unwrap();
+        let ips: Vec<_> = permitted.iter().map(|(ip, _)| *ip).collect();
+        assert_eq!(
+            ips,
+            [IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]
+        );
+    }
+
+    #[test]
+    fn lookup_one_returns_the_first_address() {
+        let resolver = in_memory(&[
+            ("two.test", &["192.0.2.1", "192.0.2.2"]),
+            ("empty.test", &[]),
+        ]);
+        assert_eq!(
+            block_on(resolver.lookup_one("two.test")).unwrap(),
+            ip("192.0.2.1")
+        );
+        assert!(matches!(
+            block_on(resolver.lookup_one("empty.test")),
+            Err(Error::NoAddresses {})
+        ));
+        assert!(matches!(
+            block_on(resolver.lookup_one("missing.test")),
+            Err(Error::NxDomain {})
+        ));
+    }
+
+    #[test]
+    fn lookup_or_falls_back() {
+        let resolver = in_memory(&[("known.test", &["192.0.2.1"])]);
+        let fallback = vec![ip("198.51.100.1")];
+        assert_eq!(
+            block_on(resolver.lookup_or("missing.test", fallback.clone())),
+            fallback
+        );
+        assert_eq!(
+            block_on(resolver.lookup_or("known.test", fallback)),
+            [ip("192.0.2.1")]
+        );
+    }
+
+    #[test]
+    fn www_fallback() {
+        let resolver = in_memory(&[
+            ("www.example.test", &["192.0.2.1"]),
+            ("www.www.missing.test", &["192.0.2.9"]),
+        ]);
+        let (name, addrs) = block_on(resolver.lookup_with_www_fallback("example.test")).unwrap();
+        assert_eq!(name, "www.example.test");
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].0, ip("192.0.2.1"));
+
+        // A name found as is, or already starting with www., is not prefixed.
+        let (name, _) = block_on(resolver.lookup_with_www_fallback("www.example.test")).unwrap();
+        assert_eq!(name, "www.example.test");
+        assert!(matches!(
+            block_on(resolver.lookup_with_www_fallback("WWW.missing.test")),
+            Err(Error::NxDomain {})
+        ));
+    }
+
+    #[test]
+    fn lookup_ip_split_by_family() {
+        let resolver = in_memory(&[("dual.test", &["192.0.2.1", "2001:db8::1", "192.0.2.2"])]);
+        let (v4, v6) = block_on(resolver.lookup_ip_split("dual.test")).unwrap();
+        let v4: Vec<_> = v4.into_iter().map(|(ip, _)| ip).collect();
+        let v6: Vec<_> = v6.into_iter().map(|(ip, _)| ip).collect();
+        assert_eq!(
+            v4,
+            [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+        );
+        assert_eq!(v6, ["2001:db8::1".parse::<Ipv6Addr>().unwrap()]);
+    }
+
+    #[test]
+    fn lookup_ttl_grouped_by_ttl() {
+        let mut resolver = in_memory(&[]);
+        let (short, long) = (Duration::from_secs(30), Duration::from_secs(300));
+        resolver.overrides.insert(
+            override_key("ttl.test"),
+            vec![
+                (ip("192.0.2.1"), long),
+                (ip("192.0.2.2"), short),
+                (ip("2001:db8::1"), long),
+            ],
+        );
+        let groups = block_on(resolver.lookup_ttl_grouped("ttl.test")).unwrap();
+        assert_eq!(
+            groups,
+            [
+                (short, vec![ip("192.0.2.2")]),
+                (long, vec![ip("192.0.2.1"), ip("2001:db8::1")]),
+            ]
+        );
     }
 }
@@ -0,0 +1,32 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::{net::IpAddr, time::Duration};
+
+use crate::StubResolver;
+
+/// The key a name is stored under in the overrides.
+pub(crate) fn override_key(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl StubResolver {
+    pub(super) fn query_overrides(
+        &self,
+        name: &str,
+    ) -> Option<impl Iterator<Item = (IpAddr, Duration)> + '_> {
+        if self.overrides.is_empty() {
+            return None;
+        }
+        self.overrides
+            .get(&override_key(name))
+            .map(|addrs| addrs.iter().copied())
+    }
+}
@@ -0,0 +1,78 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use domain::{
+    base::{name::ParsedName, rdata::UnknownRecordData, wire::ParseError, Rtype, ToName},
+    rdata,
+};
+
+use super::resolv::absolute_name;
+use crate::{answer::UriRecord, errors::Error, StubResolver};
+
+impl StubResolver {
+    /// Look up the DNAME record of the name, returning the name its subtree is redirected to.
+    pub async fn lookup_dname(&self, name: &str) -> Result<Option<String>, Error> {
+        let name = absolute_name(&self.rewrite_name(name))?;
+        let message = self.dns_query(&name, Rtype::DNAME).await?;
+        for record in message
+            .answer()?
+            .limit_to::<rdata::Dname<ParsedName<&[u8]>>>()
+        {
+            let record = record?;
+            if record.owner().name_eq(&name) {
+                return Ok(Some(record.data().dname().to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Look up the URI records of the name (RFC 7553), sorted by priority then weight.
+    pub async fn lookup_uri(&self, name: &str) -> Result<Vec<UriRecord>, Error> {
+        let name = absolute_name(&self.rewrite_name(name))?;
+        let message = self.dns_query(&name, Rtype::URI).await?;
+        let mut records = Vec::new();
+        for record in message.answer()?.limit_to::<UnknownRecordData<&[u8]>>() {
+            let record = record?;
+            if record.rtype() != Rtype::URI || !record.owner().name_eq(&name) {
+                continue;
+            }
+            // The priority and weight are followed by the target, which takes up the rest of the
+            // data.
+            let data = record.data().data();
+            if data.len() < 4 {
+                return Err(ParseError::ShortInput.into());
+            }
+            records.push(UriRecord {
+                priority: u16::from_be_bytes([data[0], data[1]]),
+                weight: u16::from_be_bytes([data[2], data[3]]),
+                target: String::from_utf8(data[4..].to_vec())?,
+            });
+        }
+        records.sort_by_key(|x| (x.priority, x.weight));
+        Ok(records)
+    }
+
+    /// Look up the serial number of the SOA record of the zone, which changes whenever the zone
+    /// does.
+    pub async fn soa_serial(&self, zone: &str) -> Result<u32, Error> {
+        let zone = absolute_name(&self.rewrite_name(zone))?;
+        let message = self.dns_query(&zone, Rtype::SOA).await?;
+        for record in message
+            .answer()?
+            .limit_to::<rdata::Soa<ParsedName<&[u8]>>>()
+        {
+            let record = record?;
+            if record.owner().name_eq(&zone) {
+                return Ok(record.data().serial().into_int());
+            }
+        }
+        Err(Error::NoRecords {})
+    }
+}
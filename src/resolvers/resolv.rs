@@ -9,165 +9,1121 @@
 //
 
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     net::{IpAddr, SocketAddr},
     rc::Rc,
     str::FromStr,
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use domain::base::{
-    iana::Class, name::UncertainName, wire::Composer, MessageBuilder, Question, Rtype,
-    StaticCompressor, ToName,
+use domain::{
+    base::{
+        iana::{Class, Opcode, OptRcode, OptionCode},
+        name::UncertainName,
+        Message, MessageBuilder, Name, Question, Rtype, StaticCompressor, ToName,
+    },
+    dep::octseq::builder::OctetsBuilder,
 };
+use monoio::io::stream::Stream;
 
-use crate::{addr::IpAddresses, errors::Error, StubResolver};
+use crate::{
+    addr::IpAddresses,
+    answer::{Answer, LookupTimings, Origin, ZoneRecord},
+    errors::Error,
+    future::{first_accepted, join_all, unordered},
+    observer::{Observer, TcpFallbackReason},
+    Family, LookupOptions, StubResolver, Transport,
+};
 
 impl StubResolver {
-    pub(super) async fn query_resolv<B>(&self, name: &str) -> Result<B, Error>
+    /// Query all the nameservers at once, returning the union of the addresses they answer with.
+    ///
+    /// The name is used as is, without the search domains. An address returned by several
+    /// nameservers is only returned once, with the shortest TTL seen for it.
+    pub async fn lookup_union<B>(&self, name: &str) -> Result<B, Error>
     where
         B: FromIterator<(IpAddr, Duration)>,
     {
-        self.dns_with_search(name).await
+        if self.nameservers.is_empty() {
+            return Err(Error::NoNameserversConfigured {});
+        }
+
+        let name = absolute_name(&self.rewrite_name(name))?;
+        let options = self.query_options();
+        let queries = self.nameservers.iter().map(|nameserver| {
+            self.query_name_and_nameserver(&name, nameserver, usize::MAX, &options)
+        });
+
+        let mut union = BTreeMap::<IpAddr, Duration>::new();
+        let mut answered = false;
+        let mut error = None;
+        for result in join_all(queries).await {
+            match result {
+                Ok(answer) => {
+                    answered = true;
+                    for (ip, ttl) in answer.addrs {
+                        union
+                            .entry(ip)
+                            .and_modify(|x| *x = (*x).min(ttl))
+                            .or_insert(ttl);
+                    }
+                }
+                Err(err) => error = Some(err),
+            }
+        }
+
+        match error {
+            Some(err) if !answered => Err(err),
+            _ => Ok(union.into_iter().collect()),
+        }
     }
 
-    async fn dns_with_search<B>(&self, name: &str) -> Result<B, Error>
-    where
-        B: FromIterator<(IpAddr, Duration)>,
-    {
-        // See if we should just use global scope.
-        let num_dots = memchr::Memchr::new(b'.', name.as_bytes()).count();
-        let global_scope = num_dots >= self.ndots as usize || name.ends_with(".");
-        let name = name.trim_end_matches('.');
-
-        if global_scope {
-            let it = self.search.iter();
-            // Try the name with the search domains.
-            let mut host = String::from(name);
-            host.push('.');
-            let size = host.len();
-            for search in it {
-                // Try the name with the search domains.
-                host.truncate(size);
-                host.push_str(search.trim_start_matches('.'));
-
-                let name = UncertainName::<Vec<u8>>::from_str(&host)?.into_absolute()?;
-                if let Ok(addrs) = self.dns_lookup(name).await {
-                    return Ok(addrs);
+    /// Query all the nameservers at once, yielding the addresses each one answers with as the
+    /// answers arrive.
+    ///
+    /// The name is used as is, without the search domains. There is one item per nameserver, a
+    /// nameserver that does not answer in time yields an error. The addresses denied by the IP
+    /// policy are left out, as with the other lookups.
+    pub fn lookup_stream_all<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Stream<Item = (SocketAddr, Result<Vec<IpAddr>, Error>)> + 'a {
+        let queries = self.nameservers.iter().map(move |nameserver| async move {
+            let result = match absolute_name(&self.rewrite_name(name)) {
+                Ok(name) => self
+                    .query_name_and_nameserver(&name, nameserver, usize::MAX, &self.query_options())
+                    .await
+                    .map(|answer| answer.addrs.into_iter().map(|(ip, _)| ip).collect()),
+                Err(err) => Err(err),
+            };
+            (*nameserver, result)
+        });
+        unordered(queries)
+    }
+
+    /// Transfer the whole zone from the nameserver (AXFR) over TCP, yielding its records as the
+    /// messages arrive, from the SOA record of the zone to the closing one.
+    pub async fn axfr(
+        &self,
+        zone: &str,
+        nameserver: SocketAddr,
+    ) -> Result<impl Stream<Item = Result<ZoneRecord, Error>>, Error> {
+        let zone = absolute_name(&self.rewrite_name(zone))?;
+        let id = fastrand::u16(..);
+        let query = create_message(id, &zone, Rtype::AXFR, &self.query_options())?;
+        crate::lookups::tcp::transfer(id, Rc::new(query), &nameserver).await
+    }
+
+    pub(super) async fn query_resolv(
+        &self,
+        name: &str,
+        max: usize,
+        opts: &LookupOptions,
+    ) -> Result<Answer, Error> {
+        if self.nameservers.is_empty() {
+            return Err(Error::NoNameserversConfigured {});
+        }
+
+        let mut options = self.query_options();
+        options.family = opts.family;
+        if let Some(transport) = &opts.transport {
+            // The transport of the lookup wins over the ones of the nameservers.
+            options.transport = transport.clone();
+            options.nameserver_transports.clear();
+        }
+        if let Some(timeout) = opts.timeout {
+            options.timeout = timeout;
+        }
+        options.edns_options = opts.edns_options.clone();
+        options.dnssec_ok = opts.dnssec_ok;
+        options.checking_disabled = opts.checking_disabled;
+        let search = opts.search.unwrap_or(true);
+        let name = self.rewrite_name(name);
+        self.dns_with_search(&name, max, search, &options).await
+    }
+
+    async fn dns_with_search(
+        &self,
+        name: &str,
+        max: usize,
+        search: bool,
+        options: &QueryOptions,
+    ) -> Result<Answer, Error> {
+        let candidates = self.search_candidates(name, search);
+        // A name that resolved ends the search, and so does a failure that another name would
+        // not change.
+        let done = |result: &Result<Answer, Error>| match result {
+            Ok(answer) => !answer.addrs.is_empty(),
+            Err(Error::CnameChainTooLong {} | Error::BlockedAddress {}) => true,
+            Err(_) => false,
+        };
+
+        let results = if self.parallel_search {
+            let lookups = candidates
+                .iter()
+                .map(|candidate| self.dns_lookup_candidate(candidate, max, options));
+            match first_accepted(lookups, done).await {
+                Ok(result) => return result,
+                Err(results) => results,
+            }
+        } else {
+            let mut results = Vec::with_capacity(candidates.len());
+            for candidate in &candidates {
+                let result = self.dns_lookup_candidate(candidate, max, options).await;
+                if done(&result) {
+                    return result;
                 }
+                results.push(result);
             }
-            FromIterator::from_iter(std::iter::empty())
+            results
+        };
+
+        // None of the names resolved. As with glibc, a name that exists without addresses wins
+        // over the ones that don't exist. Otherwise, tell which names were tried when the search
+        // domains are involved, a single name keeps its own error.
+        let mut errors = Vec::with_capacity(results.len());
+        for result in results {
+            match result {
+                Ok(answer) => return Ok(answer),
+                Err(err) => errors.push(err),
+            }
+        }
+        match errors.len() {
+            0 => Ok(Answer::default()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::AllSearchFailed {
+                attempted: candidates,
+                errors,
+            }),
+        }
+    }
+
+    /// Apply the name rewriter, if one is set, to the name as given by the user.
+    pub(super) fn rewrite_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match &self.name_rewriter {
+            Some(rewrite) => Cow::Owned(rewrite(name)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// The names to try for the given name, in order, as described in `resolv.conf(5)`.
+    ///
+    /// A name with a trailing dot is only tried as is. A name with at least `ndots` dots is tried
+    /// as is first, then with the search domains appended. Any other name is tried with the search
+    /// domains appended first, and as is last. Without `search`, any name is only tried as is.
+    fn search_candidates(&self, name: &str, search: bool) -> Vec<String> {
+        if !search || name.ends_with('.') {
+            return vec![name.trim_end_matches('.').to_owned()];
+        }
+
+        let num_dots = memchr::Memchr::new(b'.', name.as_bytes()).count();
+        let searched = self
+            .search
+            .iter()
+            .map(|search| format!("{name}.{}", search.trim_matches('.')));
+        let mut candidates = Vec::with_capacity(self.search.len() + 1);
+        if num_dots >= self.ndots as usize {
+            candidates.push(name.to_owned());
+            candidates.extend(searched);
         } else {
-            let name = UncertainName::<Vec<u8>>::from_str(name)?.into_absolute()?;
-            // Preform a DNS search on just the name.
-            self.dns_lookup(name).await
+            candidates.extend(searched);
+            candidates.push(name.to_owned());
         }
+        candidates
+    }
+
+    async fn dns_lookup_candidate(
+        &self,
+        candidate: &str,
+        max: usize,
+        options: &QueryOptions,
+    ) -> Result<Answer, Error> {
+        let start = Instant::now();
+        let name = absolute_name(candidate)?;
+        let name_construction = start.elapsed();
+        let mut answer = self.dns_lookup(name, max, options).await?;
+        answer
+            .timings
+            .get_or_insert_with(Default::default)
+            .name_construction = name_construction;
+        Ok(answer)
     }
 
     /// Preform a manual lookup for the name.
-    async fn dns_lookup<B>(&self, name: impl ToName) -> Result<B, Error>
-    where
-        B: FromIterator<(IpAddr, Duration)>,
-    {
-        let it = self.nameservers.iter();
-        for nameserver in it {
-            if let Ok(addrs) = self.query_name_and_nameserver(&name, nameserver).await {
-                return Ok(addrs);
+    async fn dns_lookup(
+        &self,
+        name: impl ToName,
+        max: usize,
+        options: &QueryOptions,
+    ) -> Result<Answer, Error> {
+        if self.family_nameservers.is_none() || options.family.is_some() {
+            return self.dns_lookup_family(&name, max, options).await;
+        }
+
+        // Each family is asked of its own nameservers, the answers are then put together.
+        let name = &name;
+        let lookups = [Family::Ipv4, Family::Ipv6].map(|family| {
+            let options = QueryOptions {
+                family: Some(family),
+                ..options.clone()
+            };
+            async move { self.dns_lookup_family(name, max, &options).await }
+        });
+        let mut merged: Option<Answer> = None;
+        let mut error = None;
+        for result in join_all(lookups).await {
+            match (result, &mut merged) {
+                (Ok(answer), None) => merged = Some(answer),
+                (Ok(answer), Some(merged)) => {
+                    merged.addrs.extend(answer.addrs);
+                    merged.addrs.truncate(max);
+                    merged.records.extend(answer.records);
+                    merged.records.truncate(max);
+                    merged.questions.extend(answer.questions);
+                    merged.transaction_ids.extend(answer.transaction_ids);
+                    merged.query_bytes += answer.query_bytes;
+                    merged.response_bytes += answer.response_bytes;
+                    merged.authenticated &= answer.authenticated;
+                    merged.nodata &= answer.nodata;
+                    merged.attempts_used = merged.attempts_used.max(answer.attempts_used);
+                }
+                (Err(err), _) => error = Some(err),
+            }
+        }
+        match (merged, error) {
+            (Some(answer), _) => Ok(answer),
+            (None, Some(err)) => Err(err),
+            (None, None) => Ok(Answer::default()),
+        }
+    }
+
+    /// Lookup the name on the nameservers of the family of the options, or all of them.
+    async fn dns_lookup_family(
+        &self,
+        name: impl ToName,
+        max: usize,
+        options: &QueryOptions,
+    ) -> Result<Answer, Error> {
+        // An answer with too few addresses is kept in case no other nameserver does better.
+        let min_answers = self.min_answers.min(max);
+        let mut partial: Option<Answer> = None;
+        let mut error = None;
+        let mut nameservers = self.nameservers_for(&name, options.family);
+        // The nameserver that last answered for the name is asked first.
+        // The families asked of their own nameservers each remember theirs.
+        let key = self.sticky_nameserver.then(|| {
+            let name = name.to_vec().to_string().to_ascii_lowercase();
+            (name, options.family)
+        });
+        if let Some(key) = &key {
+            let sticky = self.sticky_nameservers.lock().unwrap().get(key);
+            if let Some(idx) = sticky.and_then(|x| nameservers.iter().position(|y| *y == x)) {
+                nameservers.to_mut()[..=idx].rotate_right(1);
+            }
+        }
+        for nameserver in nameservers.iter() {
+            match self
+                .query_name_and_nameserver(&name, nameserver, max, options)
+                .await
+            {
+                Ok(answer) if answer.addrs.len() < min_answers => {
+                    if partial
+                        .as_ref()
+                        .map_or(true, |x| x.addrs.len() < answer.addrs.len())
+                    {
+                        partial = Some(answer);
+                    }
+                }
+                Ok(answer) => {
+                    if let Some(key) = key {
+                        self.sticky_nameservers
+                            .lock()
+                            .unwrap()
+                            .insert(key, *nameserver);
+                    }
+                    return Ok(answer);
+                }
+                // The name does not exist, asking another nameserver won't change that.
+                Err(err @ Error::NxDomain {}) => return Err(err),
+                // The chain is what the name points to, not a fault of the nameserver.
+                Err(err @ Error::CnameChainTooLong {}) => return Err(err),
+                Err(err) => {
+                    if let Some(key) = &key {
+                        self.sticky_nameservers
+                            .lock()
+                            .unwrap()
+                            .forget(key, nameserver);
+                    }
+                    error = Some(err);
+                }
             }
         }
-        Ok(FromIterator::from_iter(std::iter::empty()))
+        // Without an answer, the error of the last nameserver tells what went wrong.
+        match (partial, error) {
+            (Some(answer), _) => Ok(answer),
+            (None, Some(err)) => Err(err),
+            (None, None) => Err(Error::NoNameserversConfigured {}),
+        }
+    }
+
+    /// Query the nameservers in order for the records of the given type, returning the first
+    /// response.
+    pub(super) async fn dns_query(
+        &self,
+        name: impl ToName,
+        rtype: Rtype,
+    ) -> Result<Message<Vec<u8>>, Error> {
+        self.dns_query_with_options(name, rtype, &self.query_options())
+            .await
+    }
+
+    /// Send a query with the given opcode for the name, as is, returning the first response
+    /// as it came.
+    ///
+    /// This is meant for tooling around the other opcodes, such as NOTIFY, the response is not
+    /// interpreted in any way.
+    pub async fn query_raw(
+        &self,
+        name: &str,
+        rtype: Rtype,
+        opcode: Opcode,
+    ) -> Result<Vec<u8>, Error> {
+        let name = absolute_name(&self.rewrite_name(name))?;
+        let mut options = self.query_options();
+        options.opcode = opcode;
+        let message = self.dns_query_with_options(&name, rtype, &options).await?;
+        Ok(message.into_octets())
+    }
+
+    async fn dns_query_with_options(
+        &self,
+        name: impl ToName,
+        rtype: Rtype,
+        options: &QueryOptions,
+    ) -> Result<Message<Vec<u8>>, Error> {
+        let family = match rtype {
+            Rtype::A => Some(Family::Ipv4),
+            Rtype::AAAA => Some(Family::Ipv6),
+            _ => None,
+        };
+        let nameservers = self.nameservers_for(&name, family);
+        if nameservers.is_empty() {
+            return Err(Error::NoNameserversConfigured {});
+        }
+
+        let mut error = None;
+        for nameserver in nameservers.iter() {
+            match query_question_and_nameserver(&name, rtype, nameserver, options).await {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {}
+                Err(err) => error = Some(err),
+            }
+        }
+        Err(error.unwrap_or(Error::NoResponse {}))
+    }
+
+    /// Ask each nameserver for the SOA record of the root zone, keeping only the ones that
+    /// answer, in the same order. All of them are kept if none answers.
+    ///
+    /// The probes are sent once over UDP only, without falling back to TCP, and each of them is
+    /// bounded by the timeout.
+    pub(crate) async fn probe_nameservers(&mut self) {
+        let root = Name::<[u8]>::from_slice(b"\x00").unwrap();
+        let options = self.query_options();
+        let probes = self.nameservers.iter().map(|nameserver| {
+            let (root, options) = (&root, &options);
+            async move {
+                let id = fastrand::u16(..);
+                let query = create_message(id, root, Rtype::SOA, options)?;
+                let probe = crate::lookups::udp::query(
+                    id,
+                    Rc::new(query),
+                    nameserver,
+                    1,
+                    options.timeout,
+                    options.udp_payload_size,
+                );
+                monoio::time::timeout(options.timeout, probe).await?
+            }
+        });
+        let working: Vec<SocketAddr> = self
+            .nameservers
+            .iter()
+            .zip(join_all(probes).await)
+            .filter(|(_, result)| result.is_ok())
+            .map(|(nameserver, _)| *nameserver)
+            .collect();
+        if !working.is_empty() {
+            self.nameservers = working;
+        }
+    }
+
+    /// The nameservers to ask about the name, `.local` names go to the unicast mDNS responder if
+    /// one is set. The addresses of a family go to its own nameservers if they are set.
+    ///
+    /// The addresses of a nameserver group are asked one after the other, in the order of the
+    /// group, where the first of them would have been.
+    fn nameservers_for(&self, name: &impl ToName, family: Option<Family>) -> Cow<'_, [SocketAddr]> {
+        if let Some(mdns) = &self.mdns_unicast {
+            let local = Name::<[u8]>::from_slice(b"\x05local\x00").unwrap();
+            if name.ends_with(local) {
+                return Cow::Borrowed(std::slice::from_ref(mdns));
+            }
+        }
+        let base = match (&self.family_nameservers, family) {
+            (Some((v4, _)), Some(Family::Ipv4)) => v4,
+            (Some((_, v6)), Some(Family::Ipv6)) => v6,
+            _ => &self.nameservers,
+        };
+        if self.nameserver_groups.is_empty() {
+            return Cow::Borrowed(base);
+        }
+
+        let mut nameservers = Vec::with_capacity(base.len());
+        for nameserver in base {
+            let group = self
+                .nameserver_groups
+                .iter()
+                .find(|group| group.contains(nameserver));
+            let addrs = group.map_or(std::slice::from_ref(nameserver), |group| group.as_slice());
+            for addr in addrs {
+                if !nameservers.contains(addr) {
+                    nameservers.push(*addr);
+                }
+            }
+        }
+        Cow::Owned(nameservers)
     }
 
     /// Poll for the name on the given nameserver.
-    async fn query_name_and_nameserver<B>(
+    async fn query_name_and_nameserver(
         &self,
         name: impl ToName,
         nameserver: &SocketAddr,
-    ) -> Result<B, Error>
-    where
-        B: FromIterator<(IpAddr, Duration)>,
-    {
-        // Try to poll for an IPv4 address first.
-        let ipv4 = query_question_and_nameserver(
-            Question::new(&name, Rtype::A, Class::IN),
-            nameserver,
-            self.attempts,
-            self.timeout,
-            self.udp_payload_size,
-        );
+        max: usize,
+        options: &QueryOptions,
+    ) -> Result<Answer, Error> {
+        // Both families are asked at once, sharing the TCP connection if it comes to that.
+        let rtypes: &[Rtype] = match options.family {
+            None => &[Rtype::A, Rtype::AAAA],
+            Some(Family::Ipv4) => &[Rtype::A],
+            Some(Family::Ipv6) => &[Rtype::AAAA],
+        };
+        let start = Instant::now();
+        let exchanges = query_questions_and_nameserver(&name, rtypes, nameserver, options).await?;
+        let network = start.elapsed();
+        if exchanges.iter().any(|x| x.response.is_some()) {
+            self.record_latency(nameserver, network);
+        }
+        let start = Instant::now();
+        let query_bytes = exchanges.iter().map(|x| x.query_bytes).sum();
+        let transaction_ids = exchanges.iter().map(|x| x.id).collect();
+        let attempts_used = exchanges.iter().map(|x| x.attempts).max().unwrap_or(0);
+        let response_bytes = exchanges
+            .iter()
+            .filter_map(|x| x.response.as_ref())
+            .map(|x| x.as_slice().len())
+            .sum();
+        let responses: Vec<IpAddresses> = exchanges
+            .into_iter()
+            .filter_map(|x| x.response)
+            .map(IpAddresses::from)
+            .collect();
+        let parsing = start.elapsed();
 
-        let ipv6 = query_question_and_nameserver(
-            Question::new(&name, Rtype::AAAA, Class::IN),
-            nameserver,
-            self.attempts,
-            self.timeout,
-            self.udp_payload_size,
-        );
+        // A nameserver that failed to answer, e.g. with SERVFAIL or REFUSED, tells nothing about
+        // the name, so the next one is asked instead.
+        let failed = responses
+            .iter()
+            .map(|x| x.rcode())
+            .find(|x| *x != OptRcode::NOERROR && *x != OptRcode::NXDOMAIN);
+        if let Some(rcode) = failed {
+            return Err(Error::ServerFailure {
+                rcode: rcode.to_int(),
+            });
+        }
 
-        let (ipv4, ipv6) = monoio::join!(ipv4, ipv6);
-        let ipv4 = ipv4?;
-        let ipv6 = ipv6?;
-        let addrs = ipv4.iter().chain(ipv6.iter()).flat_map(|x| x.iter());
-        let addrs = FromIterator::from_iter(addrs);
-        Ok(addrs)
+        // A long chain is more likely to be an attack than a real setup.
+        let max_cname_chain = options.max_cname_chain as usize;
+        if responses.iter().any(|x| x.cname_chain() > max_cname_chain) {
+            return Err(Error::CnameChainTooLong {});
+        }
+
+        // The name exists if either family says so, in which case we return the addresses of
+        // whichever family has them.
+        if !responses.is_empty() && responses.iter().all(|x| !x.exists()) {
+            return Err(Error::NxDomain {});
+        }
+
+        let addrs = self.permitted(responses.iter().flat_map(|x| x.iter()), max)?;
+        let records = responses
+            .iter()
+            .flat_map(|x| x.records())
+            .filter(|x| self.ip_policy.permits(&x.ip))
+            .take(max)
+            .cloned()
+            .collect();
+        // The name exists, but neither family has addresses.
+        let nodata = !responses.is_empty()
+            && addrs.is_empty()
+            && responses.iter().all(|x| x.rcode() == OptRcode::NOERROR);
+        let authenticated = options.trust_ad
+            && !responses.is_empty()
+            && responses.iter().all(|x| x.authenticated());
+        let questions = responses
+            .iter()
+            .filter_map(|x| x.question())
+            .cloned()
+            .collect();
+        Ok(Answer {
+            addrs,
+            questions,
+            query_bytes,
+            response_bytes,
+            authenticated,
+            nodata,
+            origin: Some(Origin::Dns),
+            records,
+            transaction_ids,
+            attempts_used,
+            timings: Some(LookupTimings {
+                network,
+                parsing,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    /// The moving average of the time each nameserver took to answer, for the ones that have
+    /// answered at least once.
+    pub fn nameserver_latencies(&self) -> BTreeMap<SocketAddr, Duration> {
+        self.latencies.lock().unwrap().clone()
+    }
+
+    /// Fold the time the nameserver took to answer into its average, each new sample weighing
+    /// 1/8 as with the smoothed round-trip time of TCP (RFC 6298).
+    fn record_latency(&self, nameserver: &SocketAddr, sample: Duration) {
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(*nameserver)
+            .and_modify(|x| *x = (*x * 7 + sample) / 8)
+            .or_insert(sample);
+    }
+
+    fn query_options(&self) -> QueryOptions {
+        QueryOptions {
+            attempts: self.attempts,
+            timeout: self.timeout,
+            udp_payload_size: self.udp_payload_size,
+            padding: self.padding,
+            edns_version: self.edns_version,
+            family: None,
+            transport: self.transport.clone(),
+            opcode: Opcode::QUERY,
+            trust_ad: self.trust_ad,
+            tcp_rtypes: self.tcp_rtypes.clone(),
+            nameserver_transports: self.nameserver_transports.clone(),
+            observer: self.observer.clone(),
+            edns_options: Vec::new(),
+            max_cname_chain: self.max_cname_chain,
+            dnssec_ok: false,
+            checking_disabled: false,
+        }
     }
 }
 
-fn create_message<T: Composer + Default>(
-    id: u16,
-    question: Question<impl ToName>,
+/// The settings that shape the queries sent to a nameserver.
+#[derive(Clone)]
+struct QueryOptions {
+    attempts: u8,
+    timeout: Duration,
     udp_payload_size: u16,
-) -> Result<StaticCompressor<T>, Error> {
+    padding: Option<u16>,
+    edns_version: Option<u8>,
+    family: Option<Family>,
+    transport: Transport,
+    opcode: Opcode,
+    trust_ad: bool,
+    tcp_rtypes: Vec<Rtype>,
+    nameserver_transports: BTreeMap<SocketAddr, Transport>,
+    observer: Option<Arc<dyn Observer + Send + Sync>>,
+    edns_options: Vec<(u16, Vec<u8>)>,
+    max_cname_chain: u8,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+/// Parse the name as given by the user into an absolute name, ignoring the search domains.
+pub(super) fn absolute_name(name: &str) -> Result<Name<Vec<u8>>, Error> {
+    let name = UncertainName::<Vec<u8>>::from_str(name.trim_end_matches('.'))?.into_absolute()?;
+    Ok(name)
+}
+
+fn create_message(
+    id: u16,
+    name: &impl ToName,
+    rtype: Rtype,
+    options: &QueryOptions,
+) -> Result<Vec<u8>, Error> {
+    let message = build_message(id, name, rtype, options, None)?;
+    match options.padding {
+        Some(block) if block > 1 && options.edns_version.is_some() => {
+            // The padding option takes 4 bytes before any padding, see RFC 7830.
+            let block = block as usize;
+            let len = (block - (message.len() + 4) % block) % block;
+            build_message(id, name, rtype, options, Some(len as u16))
+        }
+        _ => Ok(message),
+    }
+}
+
+fn build_message(
+    id: u16,
+    name: &impl ToName,
+    rtype: Rtype,
+    options: &QueryOptions,
+    padding: Option<u16>,
+) -> Result<Vec<u8>, Error> {
+    if options
+        .edns_options
+        .iter()
+        .any(|(_, data)| data.len() > u16::MAX as usize)
+    {
+        return Err(Error::QueryTooLarge {});
+    }
+
     // Create the DNS query.
-    let mut message = MessageBuilder::from_target(StaticCompressor::new(Default::default()))
+    let mut message = MessageBuilder::from_target(StaticCompressor::new(Vec::new()))
         .map_err(|_| Error::AppendError {})?;
+    message.header_mut().set_opcode(options.opcode);
     message.header_mut().set_rd(true);
+    // Asks the nameserver to tell whether it validated the response (RFC 6840, section 5.7).
+    message.header_mut().set_ad(options.trust_ad);
+    message.header_mut().set_cd(options.checking_disabled);
     message.header_mut().set_id(id);
     let mut message = message.question();
-    message.push(question)?;
+    message.push(Question::new(name, rtype, Class::IN))?;
+    let Some(version) = options.edns_version else {
+        return Ok(message.finish().into_target());
+    };
     let mut message = message.additional();
     message.opt(|opt| {
-        opt.set_udp_payload_size(udp_payload_size);
+        opt.set_udp_payload_size(options.udp_payload_size);
+        opt.set_version(version);
+        opt.set_dnssec_ok(options.dnssec_ok);
+        for (code, data) in &options.edns_options {
+            opt.push_raw_option(OptionCode::from_int(*code), data.len() as u16, |target| {
+                target.append_slice(data)
+            })?;
+        }
+        // The padding goes last, so it accounts for all the other options (RFC 7830).
+        if let Some(len) = padding {
+            opt.push_raw_option(OptionCode::PADDING, len, |target| {
+                target.append_slice(&vec![0; len as usize])
+            })?;
+        }
         Ok(())
     })?;
-    Ok(message.finish())
+    Ok(message.finish().into_target())
 }
 
 /// Poll for a DNS response on the given nameserver.
 async fn query_question_and_nameserver(
-    question: Question<impl ToName>,
+    name: &impl ToName,
+    rtype: Rtype,
     nameserver: &SocketAddr,
+    options: &QueryOptions,
+) -> Result<Option<Message<Vec<u8>>>, Error> {
+    let mut exchanges = query_questions_and_nameserver(name, &[rtype], nameserver, options).await?;
+    Ok(exchanges.pop().and_then(|x| x.response))
+}
+
+/// A query sent to a nameserver, along with its response if there was one.
+struct Exchange {
+    id: u16,
+    query_bytes: usize,
+    /// The number of times the query was sent, over UDP then TCP.
     attempts: u8,
-    timeout_duration: Duration,
-    udp_payload_size: u16,
-) -> Result<Option<IpAddresses>, Error> {
-    let id = fastrand::u16(..);
-    let message = create_message::<Vec<u8>>(id, question, udp_payload_size)?;
-    let data: Rc<Vec<u8>> = Rc::from(message.into_target());
-
-    // The query may be too large, so we need to use TCP.
-    if data.len() <= udp_payload_size as usize {
-        if let Ok(Some(addrs)) = crate::lookups::udp::query(
-            id,
-            data.clone(),
+    response: Option<Message<Vec<u8>>>,
+}
+
+/// Poll for the DNS responses to the questions on the given nameserver, in the same order.
+///
+/// A question answered with BADVERS is asked again with the EDNS version the nameserver supports,
+/// or without EDNS if it does not support any version below ours (RFC 6891, section 6.1.3).
+async fn query_questions_and_nameserver(
+    name: &impl ToName,
+    rtypes: &[Rtype],
+    nameserver: &SocketAddr,
+    options: &QueryOptions,
+) -> Result<Vec<Exchange>, Error> {
+    let mut exchanges = exchange_questions(name, rtypes, nameserver, options).await?;
+    let Some(version) = options.edns_version else {
+        return Ok(exchanges);
+    };
+
+    for (exchange, rtype) in exchanges.iter_mut().zip(rtypes) {
+        let Some(response) = &exchange.response else {
+            continue;
+        };
+        let Some(opt) = response.opt() else {
+            continue;
+        };
+        if opt.rcode(response.header()) != OptRcode::BADVERS {
+            continue;
+        }
+
+        let supported = opt.version();
+        let options = QueryOptions {
+            edns_version: if supported < version {
+                Some(supported)
+            } else {
+                None
+            },
+            ..options.clone()
+        };
+        if let Some(mut retry) = exchange_questions(name, &[*rtype], nameserver, &options)
+            .await?
+            .pop()
+        {
+            retry.query_bytes += exchange.query_bytes;
+            retry.attempts = retry.attempts.saturating_add(exchange.attempts);
+            *exchange = retry;
+        }
+    }
+    Ok(exchanges)
+}
+
+/// Poll for the DNS responses to the questions on the given nameserver, in the same order.
+///
+/// The questions are sent over UDP concurrently, the ones that could not be answered that way
+/// are then sent together over a single TCP connection.
+async fn exchange_questions(
+    name: &impl ToName,
+    rtypes: &[Rtype],
+    nameserver: &SocketAddr,
+    options: &QueryOptions,
+) -> Result<Vec<Exchange>, Error> {
+    let mut queries: Vec<(u16, Rc<Vec<u8>>)> = Vec::with_capacity(rtypes.len());
+    for rtype in rtypes {
+        // The IDs tell the responses apart when they share a TCP connection.
+        let mut id = fastrand::u16(..);
+        while queries.iter().any(|(x, _)| *x == id) {
+            id = fastrand::u16(..);
+        }
+        let message = create_message(id, name, *rtype, options)?;
+        queries.push((id, Rc::from(message)));
+    }
+
+    let transport = options
+        .nameserver_transports
+        .get(nameserver)
+        .unwrap_or(&options.transport);
+
+    #[cfg(unix)]
+    if let Transport::UnixSocket { path } = transport {
+        let responses = crate::lookups::unix::query(&queries, path).await?;
+        let attempts = vec![1; queries.len()];
+        return Ok(exchanges(&queries, responses, attempts));
+    }
+
+    let udp = queries
+        .iter()
+        .zip(rtypes)
+        .map(|((id, data), rtype)| async move {
+            // The response is expected to be too large, or the query is, so we need to use TCP.
+            if *transport != Transport::Udp || options.tcp_rtypes.contains(rtype) {
+                return Ok(Err((TcpFallbackReason::Forced, 0)));
+            }
+            if data.len() > options.udp_payload_size as usize {
+                return Ok(Err((TcpFallbackReason::OversizedQuery, 0)));
+            }
+            let result = crate::lookups::udp::query(
+                *id,
+                data.clone(),
+                nameserver,
+                options.attempts,
+                options.timeout,
+                options.udp_payload_size,
+            )
+            .await;
+            match result {
+                Ok((Some(message), attempts)) => Ok(Ok((message, attempts))),
+                Ok((None, attempts)) => Ok(Err((TcpFallbackReason::Truncated, attempts))),
+                // The nameserver is not listening, fail over to the next one rather than trying TCP.
+                Err(err @ Error::ConnectionRefused { .. }) => Err(err),
+                Err(_) => Ok(Err((TcpFallbackReason::UdpFailed, options.attempts))),
+            }
+        });
+    let mut responses = Vec::with_capacity(queries.len());
+    let mut attempts = Vec::with_capacity(queries.len());
+    for result in join_all(udp).await {
+        match result? {
+            Ok((message, used)) => {
+                responses.push(Some(message));
+                attempts.push(used);
+            }
+            Err((reason, used)) => {
+                if let Some(observer) = &options.observer {
+                    observer.on_tcp_fallback(&name.to_vec().to_string(), nameserver, reason);
+                }
+                responses.push(None);
+                attempts.push(used);
+            }
+        }
+    }
+
+    // We were unable to complete some of the queries over UDP, use TCP instead.
+    let pending: Vec<usize> = (0..responses.len())
+        .filter(|idx| responses[*idx].is_none())
+        .collect();
+    if !pending.is_empty() {
+        let queries = pending.iter().map(|idx| queries[*idx].clone()).collect();
+        let tcp = crate::lookups::tcp::query(
+            queries,
             nameserver,
-            attempts,
-            timeout_duration,
-            udp_payload_size,
+            options.attempts,
+            options.timeout,
+            options.udp_payload_size,
         )
-        .await
-        {
-            return Ok(Some(addrs));
+        .await?;
+        for (idx, (response, sent)) in pending.into_iter().zip(tcp) {
+            responses[idx] = response;
+            attempts[idx] = attempts[idx].saturating_add(sent);
         }
     }
 
-    // We were unable to complete the query over UDP, use TCP instead.
-    crate::lookups::tcp::query(
-        id,
-        data,
-        nameserver,
-        attempts,
-        timeout_duration,
-        udp_payload_size,
-    )
-    .await
+    Ok(exchanges(&queries, responses, attempts))
+}
+
+fn exchanges(
+    queries: &[(u16, Rc<Vec<u8>>)],
+    responses: Vec<Option<Message<Vec<u8>>>>,
+    attempts: Vec<u8>,
+) -> Vec<Exchange> {
+    queries
+        .iter()
+        .zip(responses)
+        .zip(attempts)
+        .map(|(((id, data), response), attempts)| Exchange {
+            id: *id,
+            query_bytes: data.len(),
+            attempts,
+            response,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use domain::base::opt::UnknownOptData;
+
+    use super::*;
+
+    fn with_search(search: &[&str], ndots: u8) -> StubResolver {
+        let mut resolver = StubResolver::in_memory(Default::default());
+        resolver.search = search.iter().map(|x| x.to_string()).collect();
+        resolver.ndots = ndots;
+        resolver
+    }
+
+    fn query(options: &QueryOptions) -> Message<Vec<u8>> {
+        let name = absolute_name("example.test").unwrap();
+        let message = create_message(0x1234, &name, Rtype::A, options).unwrap();
+        Message::from_octets(message).unwrap()
+    }
+
+    #[test]
+    fn search_candidates_order() {
+        let resolver = with_search(&["a.test", "b.test."], 1);
+        assert_eq!(
+            resolver.search_candidates("host", true),
+            ["host.a.test", "host.b.test", "host"]
+        );
+        // Enough dots to be tried as is first.
+        assert_eq!(
+            resolver.search_candidates("www.host", true),
+            ["www.host", "www.host.a.test", "www.host.b.test"]
+        );
+        // An absolute name is never searched.
+        assert_eq!(resolver.search_candidates("host.", true), ["host"]);
+        assert_eq!(resolver.search_candidates("host", false), ["host"]);
+
+        let resolver = with_search(&["a.test"], 2);
+        assert_eq!(
+            resolver.search_candidates("www.host", true),
+            ["www.host.a.test", "www.host"]
+        );
+    }
+
+    /// Answer every query on a local UDP port by sending it back as its own response, with the
+    /// response code given, and count the queries.
+    fn echo_nameserver(rcode: u8) -> (SocketAddr, Rc<Cell<usize>>) {
+        let socket = monoio::net::udp::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Rc::new(Cell::new(0));
+        let counter = queries.clone();
+        monoio::spawn(async move {
+            loop {
+                let (result, mut buf) = socket.recv_from(Vec::with_capacity(512)).await;
+                let Ok((_, peer)) = result else {
+                    continue;
+                };
+                counter.set(counter.get() + 1);
+                buf[2] |= 0x80;
+                buf[3] = (buf[3] & 0xf0) | rcode;
+                let _ = socket.send_to(buf, peer).await;
+            }
+        });
+        (addr, queries)
+    }
+
+    #[test]
+    fn probe_keeps_the_nameservers_that_answer() {
+        crate::testing::block_on(async {
+            let (working, _) = echo_nameserver(0);
+            // Nothing listens on the port of a socket that is gone.
+            let dead = monoio::net::udp::UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let mut resolver = with_search(&[], 1);
+            resolver.nameservers = vec![dead, working];
+            resolver.timeout = Duration::from_millis(500);
+            resolver.probe_nameservers().await;
+            assert_eq!(resolver.nameservers, [working]);
+        });
+    }
+
+    #[test]
+    fn sticky_nameserver_is_asked_first() {
+        crate::testing::block_on(async {
+            // REFUSED, so the lookup moves on to the next nameserver.
+            let (refusing, refused) = echo_nameserver(5);
+            let (working, answered) = echo_nameserver(0);
+            let mut resolver = with_search(&[], 1).with_sticky_nameserver(true);
+            resolver.nameservers = vec![refusing, working];
+            resolver.attempts = 1;
+            resolver.timeout = Duration::from_millis(500);
+            let options = resolver.query_options();
+            let name = absolute_name("sticky.test").unwrap();
+            resolver
+                .dns_lookup_family(&name, 1, &options)
+                .await
+                .unwrap();
+            let (refused_first, answered_first) = (refused.get(), answered.get());
+            assert!(refused_first > 0 && answered_first > 0);
+
+            resolver
+                .dns_lookup_family(&name, 1, &options)
+                .await
+                .unwrap();
+            assert_eq!(refused.get(), refused_first);
+            assert!(answered.get() > answered_first);
+        });
+    }
+
+    #[test]
+    fn search_failure_tells_the_names_tried() {
+        let mut resolver = with_search(&["a.test", "b.test"], 1);
+        // Nothing listens on the port, so every name fails.
+        resolver.nameservers = vec![SocketAddr::from(([127, 0, 0, 1], 1))];
+        resolver.attempts = 1;
+        resolver.timeout = Duration::from_millis(500);
+        let options = resolver.query_options();
+        let result = crate::testing::block_on(resolver.dns_with_search("host", 1, true, &options));
+        let Err(Error::AllSearchFailed { attempted, errors }) = result else {
+            panic!("{result:?}");
+        };
+        assert_eq!(attempted, ["host.a.test", "host.b.test", "host"]);
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn message_header() {
+        let mut options = with_search(&[], 1).query_options();
+        let message = query(&options);
+        let header = message.header();
+        assert_eq!(header.id(), 0x1234);
+        assert_eq!(header.opcode(), Opcode::QUERY);
+        assert!(header.rd());
+        assert!(!header.qr());
+        assert!(!header.ad());
+        assert!(!header.cd());
+
+        options.opcode = Opcode::NOTIFY;
+        options.trust_ad = true;
+        options.checking_disabled = true;
+        let message = query(&options);
+        let header = message.header();
+        assert_eq!(header.opcode(), Opcode::NOTIFY);
+        assert!(header.ad());
+        assert!(header.cd());
+    }
+
+    #[test]
+    fn message_edns() {
+        let mut options = with_search(&[], 1).query_options();
+        let message = query(&options);
+        let opt = message.opt().unwrap();
+        assert_eq!(opt.udp_payload_size(), 512);
+        assert_eq!(opt.version(), 0);
+        assert!(!opt.dnssec_ok());
+
+        options.dnssec_ok = true;
+        assert!(query(&options).opt().unwrap().dnssec_ok());
+
+        options.edns_version = None;
+        assert!(query(&options).opt().is_none());
+    }
+
+    #[test]
+    fn message_edns_options() {
+        let mut options = with_search(&[], 1).query_options();
+        options.edns_options = vec![(65001, vec![1, 2, 3]), (65002, Vec::new())];
+        options.padding = Some(128);
+        let message = query(&options);
+        let opt = message.opt().unwrap();
+        let found: Vec<(u16, Vec<u8>)> = opt
+            .opt()
+            .iter::<UnknownOptData<&[u8]>>()
+            .map(|x| {
+                let x = x.unwrap();
+                (x.code().to_int(), x.data().to_vec())
+            })
+            .collect();
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[..2], [(65001, vec![1, 2, 3]), (65002, Vec::new())]);
+        // The padding comes after them.
+        assert_eq!(found[2].0, OptionCode::PADDING.to_int());
+
+        options.edns_options = vec![(65001, vec![0; u16::MAX as usize + 1])];
+        let name = absolute_name("example.test").unwrap();
+        assert!(matches!(
+            create_message(0x1234, &name, Rtype::A, &options),
+            Err(Error::QueryTooLarge {})
+        ));
+    }
+
+    #[test]
+    fn message_padding() {
+        let mut options = with_search(&[], 1).query_options();
+        for block in [2, 16, 128, 468] {
+            options.padding = Some(block);
+            let message = query(&options);
+            assert_eq!(message.as_slice().len() % block as usize, 0, "{block}");
+        }
+
+        // Without EDNS, there is nowhere to put the padding.
+        options.edns_version = None;
+        let padded = query(&options);
+        options.padding = None;
+        assert_eq!(padded.as_slice(), query(&options).as_slice());
+    }
 }
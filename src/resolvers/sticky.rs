@@ -0,0 +1,103 @@
+//
+// Copyright (c) 2024 Mohamed Seleem <oss@mselee.com>.
+//
+// This file is part of dnsaur.
+// See https://github.com/mselee/dnsaur for further info.
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License, v. 2.0.
+// If a copy of the MPL was not distributed with this file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use crate::Family;
+
+/// How many names the nameserver that answered is remembered for.
+const STICKY_NAMES_MAX: usize = 4096;
+
+/// A name and the family it was asked for.
+type Key = (String, Option<Family>);
+
+/// The nameserver that last answered for each name, the name used the longest ago is
+/// forgotten once there are too many.
+#[derive(Default)]
+pub(crate) struct StickyNameservers {
+    /// The nameserver of each name, along with when it was last used.
+    entries: BTreeMap<Key, (SocketAddr, u64)>,
+    /// Counts the uses, to tell which name was used the longest ago.
+    uses: u64,
+}
+
+impl StickyNameservers {
+    /// The nameserver that last answered for the name.
+    pub(crate) fn get(&mut self, key: &Key) -> Option<SocketAddr> {
+        self.uses += 1;
+        let (nameserver, used) = self.entries.get_mut(key)?;
+        *used = self.uses;
+        Some(*nameserver)
+    }
+
+    /// Remember the nameserver that answered for the name.
+    pub(crate) fn insert(&mut self, key: Key, nameserver: SocketAddr) {
+        self.uses += 1;
+        if self.entries.len() >= STICKY_NAMES_MAX && !self.entries.contains_key(&key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (nameserver, self.uses));
+    }
+
+    /// Forget the nameserver of the name, if it is the one given.
+    pub(crate) fn forget(&mut self, key: &Key, nameserver: &SocketAddr) {
+        if self.entries.get(key).is_some_and(|(x, _)| x == nameserver) {
+            self.entries.remove(key);
+        }
+    }
+
+    /// Forget the nameservers of all the names.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(idx: usize) -> Key {
+        (format!("{idx}.test"), None)
+    }
+
+    #[test]
+    fn names_used_the_longest_ago_are_forgotten() {
+        let nameserver = SocketAddr::from(([127, 0, 0, 1], 53));
+        let mut sticky = StickyNameservers::default();
+        for idx in 0..STICKY_NAMES_MAX {
+            sticky.insert(key(idx), nameserver);
+        }
+        assert_eq!(sticky.get(&key(0)), Some(nameserver));
+        sticky.insert(key(STICKY_NAMES_MAX), nameserver);
+        assert_eq!(sticky.entries.len(), STICKY_NAMES_MAX);
+        assert_eq!(sticky.get(&key(0)), Some(nameserver));
+        assert_eq!(sticky.get(&key(1)), None);
+        assert_eq!(sticky.get(&key(STICKY_NAMES_MAX)), Some(nameserver));
+    }
+
+    #[test]
+    fn forget_only_the_nameserver_given() {
+        let first = SocketAddr::from(([127, 0, 0, 1], 53));
+        let second = SocketAddr::from(([127, 0, 0, 2], 53));
+        let mut sticky = StickyNameservers::default();
+        sticky.insert(key(0), first);
+        sticky.forget(&key(0), &second);
+        assert_eq!(sticky.get(&key(0)), Some(first));
+        sticky.forget(&key(0), &first);
+        assert_eq!(sticky.get(&key(0)), None);
+    }
+}